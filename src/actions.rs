@@ -1,15 +1,35 @@
 //! GPT editing actions, interface agnostic.
 use crate::Info;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
+use byte_unit::Byte;
+use linapi::system::devices::block::Block;
 use parts::{types::*, uuid::Uuid, Gpt, Partition, PartitionBuilder, PartitionType};
 use serde::{Deserialize, Serialize};
 use std::{
-    fs,
-    io,
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    fmt, fs, io,
     io::{prelude::*, SeekFrom},
+    path::PathBuf,
+    str::FromStr,
 };
 use structopt::clap::arg_enum;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+pub mod data;
+pub mod raw;
+pub mod registry;
+pub mod verify;
+pub mod wipe;
+
+use raw::{RawGpt, Table};
+use registry::Registry;
+use verify::Location;
+
+/// Size of the partition entry array, in bytes.
+///
+/// `parts` always writes 128 entries of 128 bytes each.
+const ENTRY_ARRAY_SIZE: u64 = 128 * 128;
 
 arg_enum! {
     /// Supported formats for dumping/restoring the Gpt
@@ -19,13 +39,181 @@ arg_enum! {
     }
 }
 
-/// Either a relative or absolute end. Used by [`add_part`]
+/// Either a relative or absolute end. Used by [`add_part`] and [`resize_part`]
 #[derive(Debug, Copy, Clone)]
 pub enum End {
     Abs(Offset),
     Rel(Size),
 }
 
+/// Selects a partition by number, partition Uuid, or name.
+///
+/// Partition numbers start at 1.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    Number(usize),
+    Uuid(Uuid),
+    Name(String),
+}
+
+impl FromStr for Selector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(num) = s.parse() {
+            Ok(Selector::Number(num))
+        } else if let Ok(uuid) = s.parse() {
+            Ok(Selector::Uuid(uuid))
+        } else {
+            Ok(Selector::Name(s.into()))
+        }
+    }
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Selector::Number(num) => write!(f, "partition {}", num),
+            Selector::Uuid(uuid) => write!(f, "partition {}", uuid),
+            Selector::Name(name) => write!(f, "partition named {:?}", name),
+        }
+    }
+}
+
+/// Where [`place_in_free`] puts a new partition.
+#[derive(Debug, Copy, Clone)]
+pub enum Placement {
+    /// The first free space it fits in.
+    FirstFit,
+
+    /// The smallest free space it fits in.
+    BestFit,
+
+    /// The end of the last free space it fits in.
+    Last,
+
+    /// A specific free space, starting at 1.
+    Gap(usize),
+}
+
+impl FromStr for Placement {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "first-fit" => Ok(Placement::FirstFit),
+            "best-fit" => Ok(Placement::BestFit),
+            "last" => Ok(Placement::Last),
+            _ => match s.parse() {
+                Ok(0) => Err(anyhow!("Free space numbers start at 1")),
+                Ok(gap) => Ok(Placement::Gap(gap)),
+                Err(_) => Err(anyhow!(
+                    "Expected first-fit, best-fit, last, or a free space number"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Placement::FirstFit => write!(f, "first-fit"),
+            Placement::BestFit => write!(f, "best-fit"),
+            Placement::Last => write!(f, "last"),
+            Placement::Gap(gap) => write!(f, "free space {}", gap),
+        }
+    }
+}
+
+/// Partition attributes, by partition Uuid.
+///
+/// `parts` doesn't track these, so they're read and written separately.
+pub type Attributes = HashMap<Uuid, u64>;
+
+/// Partition numbers, starting at 1, by partition Uuid.
+///
+/// A partitions number is its slot in the partition entry array, which may
+/// have unused slots before it. `parts` doesn't track these, so they're read
+/// and written separately, like [`Attributes`].
+pub type Numbers = HashMap<Uuid, usize>;
+
+/// Well known attribute bit names.
+///
+/// Bits 48 to 63 are specific to the partition type, the names here are the
+/// common Microsoft and systemd meanings.
+const ATTRIBUTE_NAMES: &[(u8, &str)] = &[
+    (0, "required"),
+    (1, "no-block-io"),
+    (2, "legacy-bios-bootable"),
+    (60, "read-only"),
+    (61, "shadow-copy"),
+    (62, "hidden"),
+    (63, "no-automount"),
+];
+
+/// A single GPT partition attribute bit.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Attribute(u8);
+
+impl Attribute {
+    /// Mask for this attribute bit.
+    pub fn mask(self) -> u64 {
+        1 << self.0
+    }
+
+    /// All attribute bits set in `attributes`.
+    pub fn from_bits(attributes: u64) -> Vec<Attribute> {
+        (0..64)
+            .filter(|bit| attributes & (1 << bit) != 0)
+            .map(Attribute)
+            .collect()
+    }
+}
+
+impl FromStr for Attribute {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Ok(bit) = s.parse::<u8>() {
+            if bit < 64 {
+                return Ok(Attribute(bit));
+            }
+            return Err(anyhow!("Attribute bit must be between 0 and 63"));
+        }
+        ATTRIBUTE_NAMES
+            .iter()
+            .find(|(_, name)| name.eq_ignore_ascii_case(s))
+            .map(|(bit, _)| Attribute(*bit))
+            .ok_or_else(|| anyhow!("Unknown attribute {:?}", s))
+    }
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match ATTRIBUTE_NAMES.iter().find(|(bit, _)| *bit == self.0) {
+            Some((bit, name)) => write!(f, "{} ({})", bit, name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+/// Information on a connected block device.
+#[derive(Debug, Serialize)]
+pub struct DiskInfo {
+    pub name: String,
+    pub path: PathBuf,
+    pub size: Size,
+    pub model: String,
+    pub block_size: BlockSize,
+
+    /// Whether the device has a valid Gpt.
+    ///
+    /// `None` if the device couldn't be read.
+    pub gpt: Option<bool>,
+}
+
 /// Format versions. Defaults to V1.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PartitionInfoVersion {
@@ -47,6 +235,8 @@ struct PartInfo {
     uuid: Uuid,
     start: Offset,
     end: Offset,
+    #[serde(default)]
+    attributes: u64,
 }
 
 /// Portable format to handle Gpt, device, and partitions.
@@ -69,6 +259,7 @@ struct DeviceInfo {
 impl DeviceInfo {
     pub fn new(
         gpt: &Gpt,
+        attributes: &Attributes,
         block_size: BlockSize,
         device_size: Size,
         model: String,
@@ -89,13 +280,20 @@ impl DeviceInfo {
                     uuid: p.uuid(),
                     start: p.start() * block_size,
                     end: p.end() * block_size,
+                    attributes: attributes.get(&p.uuid()).copied().unwrap_or_default(),
                 })
                 .collect(),
         }
     }
 
-    pub fn into_gpt(self) -> Result<Gpt> {
+    /// Returns the Gpt, partition attributes, and block size.
+    pub fn into_gpt(self) -> Result<(Gpt, Attributes, BlockSize)> {
         let mut gpt = Gpt::new(self.uuid, self.device_size, self.block_size);
+        let attributes = self
+            .partitions
+            .iter()
+            .map(|part| (part.uuid, part.attributes))
+            .collect();
         for part in self.partitions {
             let part = PartitionBuilder::new(part.uuid, &gpt)
                 .name(&part.name)
@@ -105,14 +303,21 @@ impl DeviceInfo {
                 .finish();
             gpt.add_partition(part)?;
         }
-        Ok(gpt)
+        Ok((gpt, attributes, self.block_size))
     }
 }
 
 /// Dump the Gpt to the portable [`DeviceInfo`] format.
+///
+/// Partition attributes are read from `info.path`.
 pub fn dump(gpt: &Gpt, format: Format, info: &Info) -> Result<String> {
+    let attributes = read_attributes_path(info).unwrap_or_else(|e| {
+        warn!(%e, "Couldn't read partition attributes");
+        Attributes::new()
+    });
     let value = DeviceInfo::new(
         &gpt,
+        &attributes,
         info.block_size,
         info.disk_size,
         // FIXME: No actual need to clone here.
@@ -124,9 +329,15 @@ pub fn dump(gpt: &Gpt, format: Format, info: &Info) -> Result<String> {
     }
 }
 
-/// Restore the Gpt from the portable [`DeviceInfo`] format.
+/// Restore the Gpt, and partition attributes, from the portable [`DeviceInfo`]
+/// format.
+///
+/// Also returns the block size the dump was made with.
 // FIXME: To minimal, can do invalid restores? Bigger function?
-pub fn restore(format: Format, _version: PartitionInfoVersion) -> Result<Gpt> {
+pub fn restore(
+    format: Format,
+    _version: PartitionInfoVersion,
+) -> Result<(Gpt, Attributes, BlockSize)> {
     match format {
         Format::Json => {
             let info: DeviceInfo = serde_json::from_reader(io::stdin())?;
@@ -146,6 +357,48 @@ pub fn new_gpt<U: Into<Option<Uuid>>>(uuid: U, info: &Info) -> Gpt {
     )
 }
 
+/// Check `info.disk_size` can hold an image for [`create_image`].
+///
+/// It must be a multiple of the block size, with room for both copies of the
+/// Gpt and at least one aligned block for partitions.
+pub fn check_image_size(info: &Info) -> Result<()> {
+    let block_size = info.block_size.get();
+    let size = info.disk_size.as_bytes();
+    if !size.is_multiple_of(block_size) {
+        return Err(anyhow!(
+            "Image size {} isn't a multiple of the block size {}",
+            info.disk_size,
+            info.block_size
+        ));
+    }
+    // Aligned first partition block, backup entry array and backup header.
+    let min = (align_up(info, first_usable(info)) + entry_array_blocks(info) + 2) * block_size;
+    if size < min {
+        return Err(anyhow!(
+            "Image size {} is too small, it must be at least {} bytes",
+            info.disk_size,
+            min
+        ));
+    }
+    Ok(())
+}
+
+/// Create a new, sparse, image file at `info.path` of `info.disk_size`.
+///
+/// Fails if the file already exists, or [`check_image_size`] fails.
+pub fn create_image(info: &Info) -> Result<()> {
+    let path = info.path.display();
+    info!(%path, %info.disk_size, "Creating image");
+    check_image_size(info)?;
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&info.path)
+        .with_context(|| format!("Couldn't create {}", info.path.display()))?;
+    f.set_len(info.disk_size.as_bytes())?;
+    Ok(())
+}
+
 /// Read and return the Gpt from `source`.
 pub fn read_gpt<R: Read + Seek>(source: R, info: &Info) -> Result<Gpt> {
     Ok(Gpt::from_reader(source, info.block_size)?)
@@ -168,18 +421,23 @@ pub fn add_part<U>(
     gpt: &mut Gpt,
     info: &Info,
     uuid: U,
+    name: Option<&str>,
     partition_type: Uuid,
     start: Offset,
     end: End,
-) -> Result<()>
+) -> Result<Partition>
 where
     U: Into<Option<Uuid>>,
 {
-    info!(%partition_type, %start, ?end, "Adding partition");
+    info!(?name, %partition_type, %start, ?end, "Adding partition");
     debug!(?gpt);
     let part = PartitionBuilder::new(uuid.into().unwrap_or_else(Uuid::new_v4), &gpt)
         .start(start / info.block_size)
         .partition_type(PartitionType::from_uuid(partition_type));
+    let part = match name {
+        Some(name) => part.name(name),
+        None => part,
+    };
     let part = match end {
         End::Abs(end) => part.end(end / info.block_size),
         End::Rel(size) => part.size(size),
@@ -187,6 +445,1497 @@ where
     let part = part.finish();
     debug!(?part);
     gpt.add_partition(part)?;
+    Ok(part)
+}
+
+/// Put `part`, of type `partition_type`, in the unused partition entry
+/// `number`.
+///
+/// `part` should already have been checked, such as by [`add_part`].
+pub fn place_part(
+    raw: &mut RawGpt,
+    part: &Partition,
+    partition_type: Uuid,
+    number: usize,
+) -> Result<()> {
+    let slots = raw.primary.entries.len();
+    let entry = number
+        .checked_sub(1)
+        .and_then(|i| raw.primary.entries.get_mut(i))
+        .ok_or_else(|| anyhow!("Partition number must be between 1 and {}", slots))?;
+    if entry.is_used() {
+        return Err(anyhow!("Partition {} is already in use", number));
+    }
+    info!(number, uuid = %part.uuid(), "Placing partition");
+    *entry = raw::Entry {
+        partition_type,
+        uuid: part.uuid(),
+        start: part.start().0,
+        end: part.end().0,
+        ..Default::default()
+    };
+    entry.set_name(part.name())?;
+    Ok(())
+}
+
+/// Find the partition selected by `selector`.
+///
+/// Partition numbers are looked up in `numbers`.
+///
+/// Returns its index in [`Gpt::partitions`] and the partition.
+pub fn find_part(gpt: &Gpt, numbers: &Numbers, selector: &Selector) -> Result<(usize, Partition)> {
+    let found: Vec<(usize, Partition)> = gpt
+        .partitions()
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, part)| match selector {
+            Selector::Number(num) => numbers.get(&part.uuid()) == Some(num),
+            Selector::Uuid(uuid) => part.uuid() == *uuid,
+            Selector::Name(name) => part.name() == name.as_str(),
+        })
+        .collect();
+    match found.as_slice() {
+        [found] => Ok(*found),
+        [] => Err(anyhow!("Couldn't find {}", selector)),
+        _ => Err(anyhow!("Found multiple matches for {}", selector)),
+    }
+}
+
+/// Describe `part` by its number, or its Uuid if the number isn't known.
+fn describe_part(numbers: &Numbers, part: &Partition) -> String {
+    match numbers.get(&part.uuid()) {
+        Some(num) => num.to_string(),
+        None => part.uuid().to_string(),
+    }
+}
+
+/// Number of blocks used by one partition entry array.
+fn entry_array_blocks(info: &Info) -> u64 {
+    let block_size = info.block_size.get();
+    ENTRY_ARRAY_SIZE.div_ceil(block_size)
+}
+
+/// First block usable by partitions in a new Gpt on the device.
+pub fn first_usable(info: &Info) -> u64 {
+    // Protective MBR, primary header, primary entry array.
+    2 + entry_array_blocks(info)
+}
+
+/// Last block usable by partitions in a new Gpt on the device. Inclusive.
+pub fn last_usable(info: &Info) -> u64 {
+    // Backup entry array and backup header.
+    info.disk_size.as_bytes() / info.block_size.get() - entry_array_blocks(info) - 2
+}
+
+/// Blocks usable by partitions, from the primary header in `raw`. Inclusive.
+///
+/// These can differ from [`first_usable`] and [`last_usable`], such as after
+/// the device grows, until the Gpt is expanded.
+pub fn header_usable(raw: &RawGpt) -> (u64, u64) {
+    let header = &raw.primary.header;
+    (header.first_usable, header.last_usable)
+}
+
+/// Read the blocks usable by partitions from `source`, see [`header_usable`].
+pub fn read_usable<R: Read + Seek>(mut source: R, info: &Info) -> Result<(u64, u64)> {
+    Ok(header_usable(&RawGpt::read(&mut source, info.block_size)?))
+}
+
+/// Free space within the `usable` blocks, as inclusive block ranges, in order.
+pub fn free_space(gpt: &Gpt, usable: (u64, u64)) -> Vec<(u64, u64)> {
+    let (first, last) = usable;
+    let mut used: Vec<(u64, u64)> = gpt
+        .partitions()
+        .iter()
+        .map(|p| (p.start().0, p.end().0))
+        .collect();
+    used.sort_unstable();
+    let mut free = Vec::new();
+    let mut next = first;
+    for (start, end) in used {
+        if start > next {
+            free.push((next, start - 1));
+        }
+        next = next.max(end + 1);
+    }
+    if next <= last {
+        free.push((next, last));
+    }
+    free
+}
+
+/// First block at or after `block` that starts on an alignment boundary.
+fn align_up(info: &Info, block: u64) -> u64 {
+    let block_size = info.block_size.get();
+    let offset = info.alignment_offset;
+    let bytes = (block * block_size).max(offset) - offset;
+    (bytes.div_ceil(info.alignment) * info.alignment + offset).div_ceil(block_size)
+}
+
+/// Last block at or before `block` that starts on an alignment boundary.
+///
+/// `block` must be at or after the first boundary.
+fn align_down(info: &Info, block: u64) -> u64 {
+    let block_size = info.block_size.get();
+    let offset = info.alignment_offset;
+    let bytes = (block * block_size).saturating_sub(offset);
+    (bytes / info.alignment * info.alignment + offset) / block_size
+}
+
+/// Whether `block` starts on an alignment boundary.
+pub fn is_aligned(info: &Info, block: u64) -> bool {
+    let bytes = info.block_size.get() * block;
+    bytes >= info.alignment_offset && (bytes - info.alignment_offset).is_multiple_of(info.alignment)
+}
+
+/// Start of the next aligned partition after the existing ones, and the space
+/// remaining after it within the `usable` blocks.
+pub fn next_start(gpt: &Gpt, info: &Info, usable: (u64, u64)) -> (Offset, Size) {
+    let block_size = info.block_size.get();
+    let next = gpt
+        .partitions()
+        .iter()
+        .map(|p| p.end().0 + 1)
+        .fold(usable.0, u64::max);
+    let start = align_up(info, next);
+    let remaining = (usable.1 + 1).saturating_sub(start) * block_size;
+    (Offset(start * block_size), Size::from_bytes(remaining))
+}
+
+/// Find where to put a new partition of `size` bytes, in free space within the
+/// `usable` blocks chosen by `placement`.
+///
+/// Partitions start on an alignment boundary, except with [`Placement::Last`]
+/// when `size` is given, where they end at the end of the free space and
+/// their start is rounded down to a boundary.
+///
+/// If `size` is `None`, the partition takes all of the chosen free space.
+///
+/// Returns the start and end, for [`add_part`].
+pub fn place_in_free(
+    gpt: &Gpt,
+    info: &Info,
+    usable: (u64, u64),
+    placement: Placement,
+    size: Option<u64>,
+) -> Result<(Offset, End)> {
+    let block_size = info.block_size.get();
+    let need = size.map(|size| size.div_ceil(block_size));
+    // Aligned start and end of each free space, with its number.
+    let gaps: Vec<(usize, u64, u64)> = free_space(gpt, usable)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (start, end))| (i + 1, align_up(info, start), end))
+        .collect();
+    let fits = |(_, start, end): &&(usize, u64, u64)| match need {
+        Some(need) => start + need <= end + 1,
+        None => start <= end,
+    };
+    let gap = match placement {
+        Placement::FirstFit => gaps.iter().find(fits),
+        Placement::BestFit => gaps.iter().filter(fits).min_by_key(|(_, s, e)| e - s),
+        Placement::Last => gaps.iter().rev().find(fits),
+        Placement::Gap(num) => match gaps.iter().find(|(i, ..)| *i == num) {
+            Some(gap) if fits(&gap) => Some(gap),
+            Some(_) => return Err(anyhow!("Partition doesn't fit in free space {}", num)),
+            None => return Err(anyhow!("There is no free space {}", num)),
+        },
+    };
+    let &(num, start, end) = gap.ok_or_else(|| anyhow!("No free space fits the partition"))?;
+    let (start, end) = match (placement, need) {
+        (Placement::Last, Some(need)) => (align_down(info, end + 1 - need), end),
+        (_, Some(need)) => (start, start + need - 1),
+        (_, None) => (start, end),
+    };
+    info!(%placement, num, start, end, "Placing partition");
+    Ok((
+        Offset(start * block_size),
+        End::Abs(Offset(end * block_size)),
+    ))
+}
+
+/// Human readable size of `bytes`.
+fn human(bytes: u64) -> String {
+    Byte::from_bytes(bytes.into())
+        .get_appropriate_unit(true)
+        .to_string()
+}
+
+/// Create a human readable table of the partitions and free space on the
+/// device.
+///
+/// Free space is within the `usable` blocks, partitions are numbered using
+/// `numbers`, and their types named using `registry`.
+pub fn print(
+    gpt: &Gpt,
+    info: &Info,
+    usable: (u64, u64),
+    numbers: &Numbers,
+    registry: &Registry,
+) -> Result<String> {
+    use std::fmt::Write as _;
+
+    /// A row in the table.
+    enum Row<'a> {
+        Part(Option<usize>, &'a Partition),
+        Free(usize, u64, u64),
+    }
+
+    let block_size = info.block_size.get();
+    let mut rows: Vec<(u64, Row)> = gpt
+        .partitions()
+        .iter()
+        .map(|p| (p.start().0, Row::Part(numbers.get(&p.uuid()).copied(), p)))
+        .chain(
+            free_space(gpt, usable)
+                .into_iter()
+                .enumerate()
+                .map(|(i, (start, end))| (start, Row::Free(i + 1, start, end))),
+        )
+        .collect();
+    rows.sort_by_key(|(start, _)| *start);
+    let mut misaligned = false;
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "Disk {}: {}, {} blocks",
+        info.path.display(),
+        human(info.disk_size.as_bytes()),
+        info.disk_size.as_bytes() / block_size
+    )?;
+    if !info.model.is_empty() {
+        writeln!(out, "Model: {}", info.model)?;
+    }
+    writeln!(out, "Disk Uuid: {}", gpt.uuid())?;
+    writeln!(out, "Block size: {} bytes", block_size)?;
+    writeln!(
+        out,
+        "Alignment: {} bytes, offset {} bytes",
+        info.alignment, info.alignment_offset
+    )?;
+    writeln!(out, "Usable blocks: {} to {}", usable.0, usable.1)?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:>6} {:>12} {:>12} {:>12} {:>12} {:>12}  {:<24} {:<20} Uuid",
+        "Number", "Start", "End", "Start", "End", "Size", "Type", "Name"
+    )?;
+    for (_, row) in rows {
+        let (num, start, end) = match row {
+            Row::Part(num, p) => {
+                let mut num = num.map_or_else(|| "?".into(), |num| num.to_string());
+                if !is_aligned(info, p.start().0) {
+                    misaligned = true;
+                    num.push('*');
+                }
+                (num, p.start().0, p.end().0)
+            }
+            Row::Free(_, start, end) => (String::new(), start, end),
+        };
+        write!(
+            out,
+            "{:>6} {:>12} {:>12} {:>12} {:>12} {:>12}  ",
+            num,
+            start,
+            end,
+            human(start * block_size),
+            human((end + 1) * block_size),
+            human((end - start + 1) * block_size),
+        )?;
+        match row {
+            Row::Part(_, p) => writeln!(
+                out,
+                "{:<24} {:<20} {}",
+                registry.describe(p.partition_type().to_uuid()),
+                p.name(),
+                p.uuid()
+            )?,
+            Row::Free(num, ..) => writeln!(out, "Free space {}", num)?,
+        };
+    }
+    if misaligned {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "* Partition doesn't start on a {} alignment boundary",
+            human(info.alignment)
+        )?;
+    }
+    Ok(out)
+}
+
+/// Get information on every connected block device.
+pub fn disks() -> Result<Vec<DiskInfo>> {
+    let disks = Block::get_connected().context("Couldn't get connected devices")?;
+    let mut found = Vec::new();
+    for disk in &disks {
+        let info = match Info::new_block(disk) {
+            Ok(info) => info,
+            Err(e) => {
+                debug!(name = disk.name(), %e, "Skipping device");
+                continue;
+            }
+        };
+        let gpt = match fs::File::open(&info.path) {
+            Ok(source) => Some(read_gpt(source, &info).is_ok()),
+            Err(e) => {
+                let path = info.path.display();
+                debug!(%path, %e, "Couldn't open device");
+                None
+            }
+        };
+        found.push(DiskInfo {
+            name: info.name,
+            path: info.path,
+            size: info.disk_size,
+            model: info.model,
+            block_size: info.block_size,
+            gpt,
+        });
+    }
+    Ok(found)
+}
+
+/// What [`find`] searches for.
+#[derive(Debug, Clone)]
+pub enum Query {
+    Name(String),
+    Uuid(Uuid),
+    Type(Uuid),
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Name(name) => write!(f, "partition named {}", name),
+            Query::Uuid(uuid) => write!(f, "partition with Uuid {}", uuid),
+            Query::Type(uuid) => write!(f, "partition with type {}", uuid),
+        }
+    }
+}
+
+/// A partition found by [`find`].
+#[derive(Debug, Serialize)]
+pub struct FoundPart {
+    /// Path to the disk.
+    pub disk: PathBuf,
+
+    /// Path to the kernel partition device, if there is one.
+    pub path: Option<PathBuf>,
+
+    /// Partition number, starting at 1.
+    pub number: usize,
+
+    pub name: String,
+
+    pub uuid: Uuid,
+}
+
+/// Search the Gpt of every connected disk for partitions matching `query`.
+///
+/// Disks that can't be read, or don't have a Gpt, are skipped.
+pub fn find(query: &Query) -> Result<Vec<FoundPart>> {
+    let disks = Block::get_connected().context("Couldn't get connected devices")?;
+    let mut found = Vec::new();
+    for disk in &disks {
+        let info = match Info::new_block(disk) {
+            Ok(info) => info,
+            Err(e) => {
+                debug!(name = disk.name(), %e, "Skipping device");
+                continue;
+            }
+        };
+        let path = info.path.display();
+        let read = || -> Result<(Gpt, Numbers)> {
+            let mut source = fs::File::open(&info.path)?;
+            Ok((
+                read_gpt(&mut source, &info)?,
+                read_numbers(&mut source, &info)?,
+            ))
+        };
+        let (gpt, numbers) = match read() {
+            Ok(read) => read,
+            Err(e) => {
+                debug!(%path, %e, "Skipping device");
+                continue;
+            }
+        };
+        let nodes = disk.partitions().unwrap_or_else(|e| {
+            debug!(%path, %e, "Couldn't get kernel partitions");
+            Vec::new()
+        });
+        for part in gpt.partitions() {
+            let matches = match query {
+                Query::Name(name) => part.name() == name.as_str(),
+                Query::Uuid(uuid) => part.uuid() == *uuid,
+                Query::Type(uuid) => part.partition_type() == PartitionType::from_uuid(*uuid),
+            };
+            if !matches {
+                continue;
+            }
+            // The kernel numbers partitions by their entry, same as us.
+            let number = match numbers.get(&part.uuid()) {
+                Some(number) => *number,
+                None => {
+                    debug!(%path, uuid = %part.uuid(), "Skipping partition without an entry");
+                    continue;
+                }
+            };
+            let node = nodes
+                .iter()
+                .find(|node| node.number().ok() == Some(number as u64))
+                .and_then(|node| node.dev_path().ok().flatten());
+            found.push(FoundPart {
+                disk: info.path.clone(),
+                path: node,
+                number,
+                name: part.name().to_string(),
+                uuid: part.uuid(),
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Pretty printed JSON for `value`, ending in a newline.
+fn json_line<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    let mut out = serde_json::to_string_pretty(value)?;
+    out.push('\n');
+    Ok(out)
+}
+
+/// Create a human readable list of `found`, or JSON if `json`.
+pub fn print_found(found: &[FoundPart], json: bool) -> Result<String> {
+    use std::fmt::Write as _;
+
+    if json {
+        return json_line(found);
+    }
+    let mut out = String::new();
+    for part in found {
+        writeln!(
+            out,
+            "{} {} {} {} {}",
+            part.disk.display(),
+            part.path
+                .as_ref()
+                .map_or_else(|| "?".into(), |p| p.display().to_string()),
+            part.number,
+            part.uuid,
+            part.name
+        )?;
+    }
+    Ok(out)
+}
+
+/// Create a human readable table of the types in `registry`, or JSON if
+/// `json`.
+pub fn print_types(registry: &Registry, json: bool) -> Result<String> {
+    use std::fmt::Write as _;
+
+    if json {
+        return json_line(registry.types());
+    }
+    let mut out = String::new();
+    writeln!(out, "{:<38} {:<24} Aliases", "Uuid", "Name")?;
+    for ty in registry.types() {
+        writeln!(
+            out,
+            "{:<38} {:<24} {}",
+            ty.uuid.to_string(),
+            ty.name,
+            ty.aliases.join(", ")
+        )?;
+    }
+    Ok(out)
+}
+
+/// Create a human readable table of `disks`, or JSON if `json`.
+pub fn print_disks(disks: &[DiskInfo], json: bool) -> Result<String> {
+    use std::fmt::Write as _;
+
+    if json {
+        return json_line(disks);
+    }
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<12} {:<20} {:>12} {:>10} {:>4}  Model",
+        "Name", "Path", "Size", "Block size", "Gpt"
+    )?;
+    for disk in disks {
+        writeln!(
+            out,
+            "{:<12} {:<20} {:>12} {:>10} {:>4}  {}",
+            disk.name,
+            disk.path.display().to_string(),
+            human(disk.size.as_bytes()),
+            disk.block_size.get(),
+            match disk.gpt {
+                Some(true) => "Yes",
+                Some(false) => "No",
+                None => "?",
+            },
+            disk.model
+        )?;
+    }
+    Ok(out)
+}
+
+/// Create a copy of `gpt` containing only `parts`.
+///
+/// [`Gpt`] can only add partitions, so this is how they get replaced.
+///
+/// [`Gpt`] doesn't keep partition numbers, see [`write_gpt_attributes`] to keep
+/// them when writing.
+fn rebuild<I: IntoIterator<Item = Partition>>(gpt: &Gpt, info: &Info, parts: I) -> Result<Gpt> {
+    let mut new = Gpt::new(gpt.uuid(), info.disk_size, info.block_size);
+    for part in parts {
+        new.add_partition(part)?;
+    }
+    Ok(new)
+}
+
+/// Remove a partition from the Gpt, returning it.
+///
+/// Its entry in `raw` is cleared in place, so other partitions keep their
+/// numbers.
+pub fn remove_part(
+    gpt: &Gpt,
+    raw: &mut RawGpt,
+    info: &Info,
+    selector: &Selector,
+) -> Result<Partition> {
+    let (_, part) = find_part(gpt, &entry_numbers(raw), selector)?;
+    let index = raw.find(part.uuid())?;
+    let start = part.start() * info.block_size;
+    let end = part.end() * info.block_size;
+    info!(%selector, uuid = %part.uuid(), %start, %end, "Removing partition");
+    raw.primary.entries[index] = raw::Entry::default();
+    debug!(?part);
+    Ok(part)
+}
+
+/// Replace the partition at `index` with `part`.
+fn replace_part(gpt: &mut Gpt, info: &Info, index: usize, part: Partition) -> Result<()> {
+    let new = rebuild(
+        gpt,
+        info,
+        gpt.partitions()
+            .iter()
+            .enumerate()
+            .map(|(i, old)| if i == index { part } else { *old }),
+    )?;
+    *gpt = new;
+    Ok(())
+}
+
+/// Resize a partition by moving its end.
+///
+/// If `end` is `None`, the partition grows into all the free space directly
+/// after it, within the `usable` blocks.
+///
+/// Only the partition entry is changed. The partitions contents, such as a
+/// filesystem, are NOT touched and must be resized separately.
+pub fn resize_part(
+    gpt: &mut Gpt,
+    info: &Info,
+    usable: (u64, u64),
+    numbers: &Numbers,
+    selector: &Selector,
+    end: Option<End>,
+) -> Result<Partition> {
+    let (index, part) = find_part(gpt, numbers, selector)?;
+    let block_size = info.block_size.get();
+    let start = part.start().0;
+    // The partition can't go past the next partition, or the last usable block.
+    let limit = gpt
+        .partitions()
+        .iter()
+        .map(|p| p.start().0)
+        .filter(|s| *s > start)
+        .min()
+        .map(|s| s - 1)
+        .unwrap_or(usable.1);
+    let new_end = match end {
+        Some(End::Abs(end)) => (end / info.block_size).0,
+        Some(End::Rel(size)) => {
+            if size.as_bytes() == 0 {
+                return Err(anyhow!("Partition size can't be zero"));
+            }
+            start + size.as_bytes().div_ceil(block_size) - 1
+        }
+        None => limit,
+    };
+    if new_end < start {
+        return Err(anyhow!(
+            "New end {} is before the partition start {}",
+            new_end,
+            start
+        ));
+    }
+    if new_end > limit {
+        return Err(anyhow!(
+            "New end {} would overlap the next partition or end of usable space, at {}",
+            new_end,
+            limit + 1
+        ));
+    }
+    info!(%selector, old_end = part.end().0, new_end, "Resizing partition");
+    debug!(?gpt);
+    let part = PartitionBuilder::new(part.uuid(), gpt)
+        .name(part.name())
+        .partition_type(part.partition_type())
+        .start(part.start())
+        .end(Offset(new_end * block_size) / info.block_size)
+        .finish();
+    replace_part(gpt, info, index, part)?;
+    debug!(?part);
+    Ok(part)
+}
+
+/// Move a partition so it starts at `start`, keeping its size, within the
+/// `usable` blocks.
+///
+/// Only the partition entry is changed, see [`data::copy_within`] to move the
+/// contents.
+///
+/// Returns the partition before and after moving.
+pub fn move_part(
+    gpt: &mut Gpt,
+    info: &Info,
+    usable: (u64, u64),
+    numbers: &Numbers,
+    selector: &Selector,
+    start: Offset,
+) -> Result<(Partition, Partition)> {
+    let (index, old) = find_part(gpt, numbers, selector)?;
+    let block_size = info.block_size.get();
+    let new_start = (start / info.block_size).0;
+    let new_end = new_start + (old.end().0 - old.start().0);
+    let (first, last) = usable;
+    if new_start < first || new_end > last {
+        return Err(anyhow!(
+            "Blocks {} to {} are outside the usable range {} to {}",
+            new_start,
+            new_end,
+            first,
+            last
+        ));
+    }
+    if let Some(other) = gpt
+        .partitions()
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != index)
+        .find(|(_, p)| p.start().0 <= new_end && new_start <= p.end().0)
+    {
+        return Err(anyhow!(
+            "Blocks {} to {} would overlap partition {}",
+            new_start,
+            new_end,
+            describe_part(numbers, other.1)
+        ));
+    }
+    info!(%selector, old_start = old.start().0, new_start, "Moving partition");
+    debug!(?gpt);
+    let new = PartitionBuilder::new(old.uuid(), gpt)
+        .name(old.name())
+        .partition_type(old.partition_type())
+        .start(Offset(new_start * block_size) / info.block_size)
+        .end(Offset(new_end * block_size) / info.block_size)
+        .finish();
+    replace_part(gpt, info, index, new)?;
+    debug!(?new);
+    Ok((old, new))
+}
+
+/// Edit a partitions name, type, or Uuid.
+///
+/// Fields that are `None` are left unchanged.
+///
+/// If the Uuid changes, its entries in `numbers` and `attributes` move to the
+/// new Uuid.
+#[allow(clippy::too_many_arguments)]
+pub fn edit_part(
+    gpt: &mut Gpt,
+    info: &Info,
+    numbers: &mut Numbers,
+    attributes: &mut Attributes,
+    selector: &Selector,
+    name: Option<&str>,
+    partition_type: Option<Uuid>,
+    uuid: Option<Uuid>,
+) -> Result<Partition> {
+    if name.is_none() && partition_type.is_none() && uuid.is_none() {
+        return Err(anyhow!("Nothing to edit"));
+    }
+    let (index, old) = find_part(gpt, numbers, selector)?;
+    if let Some(uuid) = uuid {
+        if gpt
+            .partitions()
+            .iter()
+            .enumerate()
+            .any(|(i, p)| i != index && p.uuid() == uuid)
+        {
+            return Err(anyhow!("Partition Uuid {} is already in use", uuid));
+        }
+    }
+    info!(%selector, ?name, ?partition_type, ?uuid, "Editing partition");
+    debug!(?gpt);
+    let new = PartitionBuilder::new(uuid.unwrap_or_else(|| old.uuid()), gpt)
+        .name(name.unwrap_or_else(|| old.name()))
+        .partition_type(
+            partition_type
+                .map(PartitionType::from_uuid)
+                .unwrap_or_else(|| old.partition_type()),
+        )
+        .start(old.start())
+        .end(old.end())
+        .finish();
+    replace_part(gpt, info, index, new)?;
+    if new.uuid() != old.uuid() {
+        if let Some(num) = numbers.remove(&old.uuid()) {
+            numbers.insert(new.uuid(), num);
+        }
+        if let Some(attrs) = attributes.remove(&old.uuid()) {
+            attributes.insert(new.uuid(), attrs);
+        }
+    }
+    debug!(?new);
+    Ok(new)
+}
+
+/// Copy the partition layout of `gpt` to a new Gpt for the device `target`.
+///
+/// Unless `keep_uuids`, the new Gpt and its partitions get new Uuids.
+/// Partitions keep their `numbers` and `attributes`.
+///
+/// Fails if any partition doesn't fit on `target`, or isn't a whole number of
+/// its blocks.
+pub fn replicate(
+    gpt: &Gpt,
+    numbers: &Numbers,
+    attributes: &Attributes,
+    info: &Info,
+    target: &Info,
+    keep_uuids: bool,
+) -> Result<(Gpt, Numbers, Attributes)> {
+    let path = target.path.display();
+    let uuid = if keep_uuids {
+        gpt.uuid()
+    } else {
+        Uuid::new_v4()
+    };
+    info!(%path, %uuid, "Replicating GPT");
+    let block_size = info.block_size.get();
+    let target_block = target.block_size.get();
+    let mut new = Gpt::new(uuid, target.disk_size, target.block_size);
+    let mut new_numbers = Numbers::new();
+    let mut new_attributes = Attributes::new();
+    for part in gpt.partitions() {
+        let start = part.start().0 * block_size;
+        // Exclusive
+        let end = (part.end().0 + 1) * block_size;
+        if !start.is_multiple_of(target_block) || !end.is_multiple_of(target_block) {
+            return Err(anyhow!(
+                "Partition {} isn't aligned to the {} byte blocks of {}",
+                describe_part(numbers, part),
+                target_block,
+                path
+            ));
+        }
+        let (start, end) = (start / target_block, end / target_block - 1);
+        if start < first_usable(target) || end > last_usable(target) {
+            return Err(anyhow!(
+                "Partition {} doesn't fit on {}",
+                describe_part(numbers, part),
+                path
+            ));
+        }
+        let uuid = if keep_uuids {
+            part.uuid()
+        } else {
+            Uuid::new_v4()
+        };
+        if let Some(num) = numbers.get(&part.uuid()) {
+            new_numbers.insert(uuid, *num);
+        }
+        if let Some(attributes) = attributes.get(&part.uuid()) {
+            new_attributes.insert(uuid, *attributes);
+        }
+        let part = PartitionBuilder::new(uuid, &new)
+            .name(part.name())
+            .partition_type(part.partition_type())
+            .start(Offset(start * target_block) / target.block_size)
+            .end(Offset(end * target_block) / target.block_size)
+            .finish();
+        debug!(?part);
+        new.add_partition(part)?;
+    }
+    Ok((new, new_numbers, new_attributes))
+}
+
+/// Read the partition attributes from `source`.
+pub fn read_attributes<R: Read + Seek>(mut source: R, info: &Info) -> Result<Attributes> {
+    let raw = RawGpt::read(&mut source, info.block_size)?;
+    Ok(raw
+        .primary
+        .entries
+        .iter()
+        .filter(|e| e.is_used())
+        .map(|e| (e.uuid, e.attributes))
+        .collect())
+}
+
+/// Read the partition attributes from `path`
+pub fn read_attributes_path(info: &Info) -> Result<Attributes> {
+    let source = fs::File::open(&info.path)
+        .with_context(|| format!("Couldn't open {}", info.path.display()))?;
+    read_attributes(source, info)
+}
+
+/// Partition numbers in `raw`.
+pub fn entry_numbers(raw: &RawGpt) -> Numbers {
+    raw.primary
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_used())
+        .map(|(i, e)| (e.uuid, i + 1))
+        .collect()
+}
+
+/// Read the partition numbers from `source`.
+pub fn read_numbers<R: Read + Seek>(mut source: R, info: &Info) -> Result<Numbers> {
+    Ok(entry_numbers(&RawGpt::read(&mut source, info.block_size)?))
+}
+
+/// Move the partition entries in `raw` to their slots from `numbers`.
+///
+/// Partitions without a number, or whose slot is taken, get the first unused
+/// slot.
+///
+/// Returns whether any entry moved.
+fn renumber(raw: &mut RawGpt, numbers: &Numbers) -> Result<bool> {
+    let entries = &mut raw.primary.entries;
+    let mut new = vec![raw::Entry::default(); entries.len()];
+    let mut rest = Vec::new();
+    for entry in entries.iter().filter(|e| e.is_used()) {
+        match numbers
+            .get(&entry.uuid)
+            .and_then(|num| num.checked_sub(1))
+            .filter(|i| new.get(*i).is_some_and(|e| !e.is_used()))
+        {
+            Some(i) => new[i] = *entry,
+            None => rest.push(*entry),
+        }
+    }
+    for entry in rest {
+        let i = new
+            .iter()
+            .position(|e| !e.is_used())
+            .ok_or_else(|| anyhow!("No unused partition entries"))?;
+        new[i] = entry;
+    }
+    let moved = *entries != new;
+    *entries = new;
+    Ok(moved)
+}
+
+/// Write partition attributes to the Gpt in `dest`.
+///
+/// Partitions not in `attributes` are left alone.
+pub fn write_attributes<F: Read + Write + Seek>(
+    mut dest: F,
+    info: &Info,
+    attributes: &Attributes,
+) -> Result<()> {
+    debug!(?attributes, "Writing partition attributes");
+    let mut raw = RawGpt::read(&mut dest, info.block_size)?;
+    for entry in raw.primary.entries.iter_mut().filter(|e| e.is_used()) {
+        if let Some(attributes) = attributes.get(&entry.uuid) {
+            entry.attributes = *attributes;
+        }
+    }
+    raw.write(&mut dest, info.block_size)?;
+    Ok(())
+}
+
+/// Write partition attributes to the Gpt in `path`
+pub fn write_attributes_path(attributes: &Attributes, info: &Info) -> Result<()> {
+    let dest = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&info.path)
+        .with_context(|| format!("Couldn't open {}", info.path.display()))?;
+    write_attributes(dest, info, attributes)
+}
+
+/// Set and clear attribute bits on the partition selected by `selector`.
+///
+/// Returns the new attributes.
+pub fn update_attributes(
+    gpt: &Gpt,
+    raw: &mut RawGpt,
+    selector: &Selector,
+    set: &[Attribute],
+    clear: &[Attribute],
+) -> Result<u64> {
+    let (_, part) = find_part(gpt, &entry_numbers(raw), selector)?;
+    let index = raw.find(part.uuid())?;
+    let entry = &mut raw.primary.entries[index];
+    let old = entry.attributes;
+    for attr in set {
+        entry.attributes |= attr.mask();
+    }
+    for attr in clear {
+        entry.attributes &= !attr.mask();
+    }
+    if old != entry.attributes {
+        info!(
+            %selector,
+            old = format_args!("{:#018x}", old),
+            new = format_args!("{:#018x}", entry.attributes),
+            "Changing partition attributes"
+        );
+    }
+    Ok(entry.attributes)
+}
+
+/// Check the primary Gpt in `raw` can be trusted before writing it.
+///
+/// [`RawGpt::write`] copies the primary over the backup, so writing a damaged
+/// primary would also destroy a good backup.
+///
+/// Fails if the primary has CRC problems, unless `force`.
+pub fn check_primary(raw: &RawGpt, force: bool) -> Result<()> {
+    let problems = verify::check_crcs(&raw.primary, Location::Primary);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in &problems {
+        warn!(%problem);
+    }
+    if force {
+        warn!("Writing a damaged primary Gpt anyway");
+        return Ok(());
+    }
+    Err(anyhow!(
+        "Primary Gpt is damaged, see `verify` and `repair`, or use `--force`"
+    ))
+}
+
+/// Change the disk Uuid.
+pub fn set_disk_uuid(raw: &mut RawGpt, uuid: Uuid) {
+    let old = raw.primary.header.disk_uuid;
+    info!(%old, new = %uuid, "Changing disk Uuid");
+    raw.primary.header.disk_uuid = uuid;
+}
+
+/// Generate new random Uuids for the disk and partitions.
+///
+/// If `partitions` is empty, every partition gets a new Uuid, otherwise only
+/// the selected partitions do.
+///
+/// Returns the old and new Uuids of every changed partition.
+pub fn randomize_uuids(
+    gpt: &Gpt,
+    raw: &mut RawGpt,
+    disk: bool,
+    partitions: &[Selector],
+) -> Result<Vec<(Uuid, Uuid)>> {
+    if disk {
+        set_disk_uuid(raw, Uuid::new_v4());
+    }
+    let numbers = entry_numbers(raw);
+    let uuids: Vec<Uuid> = if partitions.is_empty() {
+        gpt.partitions().iter().map(|p| p.uuid()).collect()
+    } else {
+        partitions
+            .iter()
+            .map(|sel| find_part(gpt, &numbers, sel).map(|(_, p)| p.uuid()))
+            .collect::<Result<_>>()?
+    };
+    let mut changed = Vec::new();
+    for old in uuids {
+        let index = raw.find(old)?;
+        let new = Uuid::new_v4();
+        info!(%old, %new, "Changing partition Uuid");
+        raw.primary.entries[index].uuid = new;
+        changed.push((old, new));
+    }
+    Ok(changed)
+}
+
+/// Rebuild one copy of the Gpt in `source` from the other.
+///
+/// If `from` is `None` the valid copy is used, which must be the only valid
+/// one. A copy is valid if it can be read and both its CRCs are correct.
+///
+/// A rebuilt backup goes at the end of the device. If the primary expects it
+/// somewhere else, such as on a grown device, the primary is updated to match.
+/// A rebuilt primary expects the backup where it was found.
+///
+/// Returns which copies were rebuilt or updated, and the copies, in the order
+/// they should be written.
+/// They still need to be written.
+pub fn repair<R: Read + Seek>(
+    mut source: R,
+    info: &Info,
+    from: Option<Location>,
+) -> Result<Vec<(Location, Table)>> {
+    let last_lba = info.disk_size.as_bytes() / info.block_size.get() - 1;
+    let check = |copy, table: Result<Table>, lba, alt_lba| match table {
+        Ok(table) => {
+            for problem in verify::check_table(&table, info, copy, lba, alt_lba) {
+                info!(%problem);
+            }
+            let valid = verify::check_crcs(&table, copy).is_empty();
+            (Some(table), valid)
+        }
+        Err(e) => {
+            info!(%copy, %e, "Couldn't read Gpt");
+            (None, false)
+        }
+    };
+    let primary = Table::read(&mut source, 1, info.block_size);
+    let (backup_lba, backup) =
+        verify::read_backup(&mut source, info, primary.as_ref().ok().map(|t| &t.header));
+    let (primary, primary_valid) = check(Location::Primary, primary, 1, backup_lba);
+    let (backup, backup_valid) = check(Location::Backup, backup, backup_lba, 1);
+    let from = match from {
+        Some(from) => from,
+        None => match (primary_valid, backup_valid) {
+            (true, true) => return Err(anyhow!("Both copies are valid, nothing to repair")),
+            (true, false) => Location::Primary,
+            (false, true) => Location::Backup,
+            (false, false) => return Err(anyhow!("Neither copy is valid, can't repair")),
+        },
+    };
+    let (table, valid) = match from {
+        Location::Primary => (primary, primary_valid),
+        Location::Backup => (backup, backup_valid),
+    };
+    let mut table = table.ok_or_else(|| anyhow!("Couldn't read {} Gpt", from))?;
+    if !valid {
+        warn!(%from, "Repairing from a copy with bad CRCs");
+    }
+    let entry_blocks = table.header.entries_len().div_ceil(info.block_size.get());
+    let mut tables = Vec::new();
+    match from {
+        Location::Primary => {
+            let entries_lba = last_lba - entry_blocks;
+            if table.header.last_usable >= entries_lba {
+                return Err(anyhow!(
+                    "Partitions may use blocks up to {}, but the last usable block is {}",
+                    table.header.last_usable,
+                    entries_lba - 1
+                ));
+            }
+            let primary = if table.header.alt_lba != last_lba {
+                info!(
+                    old = table.header.alt_lba,
+                    new = last_lba,
+                    "Moving backup Gpt to the end of the device"
+                );
+                let mut primary = table.clone();
+                primary.header.alt_lba = last_lba;
+                primary.update_crc();
+                Some(primary)
+            } else {
+                None
+            };
+            table.header.this_lba = last_lba;
+            table.header.alt_lba = 1;
+            table.header.entries_lba = entries_lba;
+            table.update_crc();
+            info!(%from, rebuilt = %Location::Backup, "Rebuilding Gpt");
+            tables.push((Location::Backup, table));
+            tables.extend(primary.map(|primary| (Location::Primary, primary)));
+        }
+        Location::Backup => {
+            table.header.this_lba = 1;
+            table.header.alt_lba = backup_lba;
+            table.header.entries_lba = 2;
+            table.update_crc();
+            info!(%from, rebuilt = %Location::Primary, "Rebuilding Gpt");
+            tables.push((Location::Primary, table));
+        }
+    }
+    debug!(?tables);
+    Ok(tables)
+}
+
+/// Move the backup Gpt to the end of a grown device, and make the new space
+/// usable.
+///
+/// If `grow` is true, the last partition is also grown to the new end of
+/// usable space.
+///
+/// Returns the old backup header LBA, which should be cleared after writing.
+pub fn expand(raw: &mut RawGpt, info: &Info, grow: bool) -> Result<u64> {
+    let last_lba = info.disk_size.as_bytes() / info.block_size.get() - 1;
+    let old = raw.primary.header.alt_lba;
+    if old > last_lba {
+        return Err(anyhow!(
+            "Device has shrunk, backup Gpt at {} is past the end at {}",
+            old,
+            last_lba
+        ));
+    }
+    if old == last_lba && !grow {
+        return Err(anyhow!("Backup Gpt is already at the end of the device"));
+    }
+    let entry_blocks = raw
+        .backup
+        .header
+        .entries_len()
+        .div_ceil(info.block_size.get());
+    let backup = &mut raw.backup.header;
+    backup.this_lba = last_lba;
+    backup.alt_lba = 1;
+    backup.entries_lba = last_lba - entry_blocks;
+    let last_usable = backup.entries_lba - 1;
+    let primary = &mut raw.primary.header;
+    info!(
+        old,
+        new = last_lba,
+        old_last_usable = primary.last_usable,
+        last_usable,
+        "Moving backup Gpt"
+    );
+    primary.alt_lba = last_lba;
+    primary.last_usable = last_usable;
+    if grow {
+        let last = raw
+            .primary
+            .entries
+            .iter_mut()
+            .filter(|e| e.is_used())
+            .max_by_key(|e| e.end)
+            .ok_or_else(|| anyhow!("No partition to grow"))?;
+        info!(uuid = %last.uuid, old_end = last.end, new_end = last_usable, "Growing last partition");
+        last.end = last_usable;
+    }
+    Ok(old)
+}
+
+/// Sort the partition entries by start, and remove any unused entries between
+/// them.
+///
+/// Returns the old and new partition numbers, for every partition.
+pub fn sort_entries(raw: &mut RawGpt) -> Vec<(usize, usize)> {
+    let entries = &mut raw.primary.entries;
+    let mut used: Vec<(usize, raw::Entry)> = entries
+        .iter()
+        .copied()
+        .enumerate()
+        .filter(|(_, e)| e.is_used())
+        .collect();
+    used.sort_by_key(|(_, e)| e.start);
+    for entry in entries.iter_mut() {
+        *entry = raw::Entry::default();
+    }
+    let mut numbers = Vec::new();
+    for (new, (old, entry)) in used.into_iter().enumerate() {
+        entries[new] = entry;
+        if old != new {
+            info!(old = old + 1, new = new + 1, uuid = %entry.uuid, "Renumbering partition");
+        }
+        numbers.push((old + 1, new + 1));
+    }
+    numbers
+}
+
+/// Locations of the Gpt structures in `source`.
+///
+/// Returns a description, byte offset, and length for each.
+///
+/// Where a header can be read its partition entry array location is used,
+/// otherwise the standard location is assumed.
+pub fn gpt_regions<R: Read + Seek>(
+    mut source: R,
+    info: &Info,
+    keep_mbr: bool,
+) -> Vec<(String, u64, u64)> {
+    let block_size = info.block_size.get();
+    let last_lba = info.disk_size.as_bytes() / block_size - 1;
+    let entry_blocks = entry_array_blocks(info);
+    let old_backup = Table::read(&mut source, 1, info.block_size)
+        .map(|t| t.header.alt_lba)
+        .ok()
+        .filter(|alt| *alt > entry_blocks + 1 && *alt < last_lba);
+    let mut regions = Vec::new();
+    if !keep_mbr {
+        regions.push(("Protective MBR".into(), 0, block_size));
+    }
+    let mut add = |name: &str, lba: u64, entries_lba: u64| {
+        let entries = Table::read(&mut source, lba, info.block_size)
+            .map(|t| (t.header.entries_lba * block_size, t.header.entries_len()))
+            .ok()
+            .filter(|(off, len)| off + len <= info.disk_size.as_bytes())
+            .unwrap_or((entries_lba * block_size, entry_blocks * block_size));
+        regions.push((format!("{} header", name), lba * block_size, block_size));
+        regions.push((format!("{} partition entries", name), entries.0, entries.1));
+    };
+    add("Primary", 1, 2);
+    add("Backup", last_lba, last_lba - entry_blocks);
+    // Left behind on a grown device.
+    if let Some(alt) = old_backup {
+        add("Old backup", alt, alt - entry_blocks);
+    }
+    regions
+}
+
+/// Hash of one region of the device.
+#[derive(Debug, Serialize)]
+pub struct Checksum {
+    /// Partition name, or what the region is.
+    pub name: String,
+
+    /// Byte offset.
+    pub offset: u64,
+
+    /// Length, in bytes.
+    pub len: u64,
+
+    pub hash: String,
+}
+
+/// Hashes of a device, from [`checksum`].
+#[derive(Debug, Serialize)]
+pub struct Checksums {
+    pub algorithm: String,
+
+    /// Partitions, keyed by partition Uuid.
+    pub partitions: BTreeMap<String, Checksum>,
+
+    /// Gpt structures and free space, in order.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub regions: Vec<Checksum>,
+}
+
+/// Hash the data in each partition on `source`.
+///
+/// With `regions`, also hash the protective MBR, both Gpt copies, and free
+/// space.
+pub fn checksum<R: Read + Seek>(
+    mut source: R,
+    gpt: &Gpt,
+    info: &Info,
+    algorithm: data::Algorithm,
+    regions: bool,
+) -> Result<Checksums> {
+    let block_size = info.block_size.get();
+    let mut sums = Checksums {
+        algorithm: algorithm.to_string(),
+        partitions: BTreeMap::new(),
+        regions: Vec::new(),
+    };
+    for part in gpt.partitions() {
+        let offset = part.start().0 * block_size;
+        let len = (part.end().0 - part.start().0 + 1) * block_size;
+        sums.partitions.insert(
+            part.uuid().to_string(),
+            Checksum {
+                name: part.name().to_string(),
+                offset,
+                len,
+                hash: data::hash(&mut source, offset, len, algorithm)?,
+            },
+        );
+    }
+    if regions {
+        let mut found = gpt_regions(&mut source, info, false);
+        let usable = read_usable(&mut source, info)?;
+        found.extend(free_space(gpt, usable).into_iter().map(|(start, end)| {
+            (
+                "Free space".to_string(),
+                start * block_size,
+                (end - start + 1) * block_size,
+            )
+        }));
+        found.sort_by_key(|(_, offset, _)| *offset);
+        for (name, offset, len) in found {
+            sums.regions.push(Checksum {
+                hash: data::hash(&mut source, offset, len, algorithm)?,
+                name,
+                offset,
+                len,
+            });
+        }
+    }
+    Ok(sums)
+}
+
+/// Format `sums`, either as JSON or one line per region.
+pub fn print_checksums(sums: &Checksums, json: bool) -> Result<String> {
+    use std::fmt::Write as _;
+
+    if json {
+        return json_line(sums);
+    }
+    let mut out = String::new();
+    for (uuid, sum) in &sums.partitions {
+        writeln!(out, "{}  {}  {}", sum.hash, uuid, sum.name)?;
+    }
+    for sum in &sums.regions {
+        writeln!(
+            out,
+            "{}  {} at byte {}, {} bytes",
+            sum.hash, sum.name, sum.offset, sum.len
+        )?;
+    }
+    Ok(out)
+}
+
+/// Raw primary and backup Gpt headers, from [`headers`].
+///
+/// A copy is `None` if it couldn't be read.
+#[derive(Debug, Serialize)]
+pub struct Headers {
+    pub primary: Option<raw::Header>,
+    pub backup: Option<raw::Header>,
+}
+
+/// Read the raw primary and backup Gpt headers from `source`.
+///
+/// The backup is read from where the primary header says it is, or from the
+/// last block of the device if the primary can't be read, see
+/// [`verify::read_backup`].
+/// Nothing is validated, so this works on damaged tables.
+pub fn headers<R: Read + Seek>(mut source: R, info: &Info) -> Headers {
+    let header = |copy: Location, table: Result<Table>| match table {
+        Ok(table) => Some(table.header),
+        Err(e) => {
+            warn!(%copy, %e, "Couldn't read Gpt header");
+            None
+        }
+    };
+    let primary = header(
+        Location::Primary,
+        Table::read(&mut source, 1, info.block_size),
+    );
+    let (_, backup) = verify::read_backup(&mut source, info, primary.as_ref());
+    Headers {
+        primary,
+        backup: header(Location::Backup, backup),
+    }
+}
+
+/// Format `headers` side by side, or as JSON if `json`.
+pub fn print_headers(headers: &Headers, json: bool) -> Result<String> {
+    use std::fmt::Write as _;
+
+    if json {
+        return json_line(headers);
+    }
+    type Field = fn(&raw::Header) -> String;
+    let fields: &[(&str, Field)] = &[
+        ("Revision", |h| format!("{:#010x}", h.revision)),
+        ("Header size", |h| h.header_size.to_string()),
+        ("Header CRC32", |h| format!("{:#010x}", h.header_crc32)),
+        ("Current LBA", |h| h.this_lba.to_string()),
+        ("Backup LBA", |h| h.alt_lba.to_string()),
+        ("First usable LBA", |h| h.first_usable.to_string()),
+        ("Last usable LBA", |h| h.last_usable.to_string()),
+        ("Disk Uuid", |h| h.disk_uuid.to_string()),
+        ("Partition entries LBA", |h| h.entries_lba.to_string()),
+        ("Partition entries", |h| h.entries.to_string()),
+        ("Partition entry size", |h| h.entry_size.to_string()),
+        ("Partition entries CRC32", |h| {
+            format!("{:#010x}", h.entries_crc32)
+        }),
+    ];
+    let cell = |header: &Option<raw::Header>, field: Field| {
+        header
+            .as_ref()
+            .map_or_else(|| "Unreadable".to_string(), field)
+    };
+    let mut out = String::new();
+    writeln!(out, "{:<24} {:<38} Backup", "", "Primary")?;
+    for (name, field) in fields {
+        writeln!(
+            out,
+            "{:<24} {:<38} {}",
+            name,
+            cell(&headers.primary, *field),
+            cell(&headers.backup, *field)
+        )?;
+    }
+    Ok(out)
+}
+
+/// Describe what writing `table` will do, in human readable form.
+pub fn describe_table(table: &Table) -> Result<String> {
+    use std::fmt::Write as _;
+
+    let header = &table.header;
+    let mut out = String::new();
+    writeln!(out, "Header LBA: {}", header.this_lba)?;
+    writeln!(out, "Alternate LBA: {}", header.alt_lba)?;
+    writeln!(out, "Partition entries LBA: {}", header.entries_lba)?;
+    writeln!(
+        out,
+        "Usable LBAs: {} to {}",
+        header.first_usable, header.last_usable
+    )?;
+    writeln!(out, "Disk Uuid: {}", header.disk_uuid)?;
+    writeln!(
+        out,
+        "Partition entries: {} of {} bytes",
+        header.entries, header.entry_size
+    )?;
+    for (i, entry) in table
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_used())
+    {
+        writeln!(
+            out,
+            "Partition {}: {} to {}, {} {:?}",
+            i + 1,
+            entry.start,
+            entry.end,
+            entry.uuid,
+            entry.name()
+        )?;
+    }
+    Ok(out)
+}
+
+/// Write the Gpt to `dest`, then restore the partition `numbers` and
+/// `attributes` that writing cleared.
+///
+/// Partitions without a number, such as new ones, get the lowest unused
+/// number.
+pub fn write_gpt_attributes<F: Read + Write + Seek>(
+    gpt: &Gpt,
+    mut dest: F,
+    info: &Info,
+    numbers: &Numbers,
+    attributes: &Attributes,
+) -> Result<()> {
+    write_gpt(gpt, &mut dest, info)?;
+    let mut raw = RawGpt::read(&mut dest, info.block_size)?;
+    let moved = renumber(&mut raw, numbers)?;
+    if moved || attributes.values().any(|a| *a != 0) {
+        debug!(
+            ?numbers,
+            ?attributes,
+            "Restoring partition numbers and attributes"
+        );
+        for entry in raw.primary.entries.iter_mut().filter(|e| e.is_used()) {
+            if let Some(attributes) = attributes.get(&entry.uuid) {
+                entry.attributes = *attributes;
+            }
+        }
+        raw.write(&mut dest, info.block_size)?;
+    }
     Ok(())
 }
 
@@ -215,3 +1964,66 @@ pub fn write_gpt_path(gpt: &Gpt, info: &Info) -> Result<()> {
     write_gpt(gpt, dest, info)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uuid(n: u8) -> Uuid {
+        format!("00000000-0000-4000-8000-{:012}", n)
+            .parse()
+            .unwrap()
+    }
+
+    /// A [`RawGpt`] with partitions `uuids` packed into the first entries, as
+    /// `parts` writes them.
+    fn packed(uuids: &[u8]) -> RawGpt {
+        let mut entries = vec![raw::Entry::default(); 8];
+        for (entry, n) in entries.iter_mut().zip(uuids) {
+            entry.partition_type = uuid(100);
+            entry.uuid = uuid(*n);
+        }
+        let header = raw::Header {
+            revision: 0,
+            header_size: 0,
+            header_crc32: 0,
+            this_lba: 0,
+            alt_lba: 0,
+            first_usable: 0,
+            last_usable: 0,
+            disk_uuid: Uuid::nil(),
+            entries_lba: 0,
+            entries: 0,
+            entry_size: 0,
+            entries_crc32: 0,
+        };
+        let table = Table { header, entries };
+        RawGpt {
+            primary: table.clone(),
+            backup: table,
+        }
+    }
+
+    #[test]
+    fn renumber_keeps_slots() {
+        let mut raw = packed(&[1, 3, 4]);
+        let numbers: Numbers = vec![(uuid(1), 1), (uuid(3), 3), (uuid(4), 6)]
+            .into_iter()
+            .collect();
+        assert!(renumber(&mut raw, &numbers).unwrap());
+        assert_eq!(entry_numbers(&raw), numbers);
+        assert!(!renumber(&mut raw, &numbers).unwrap());
+    }
+
+    #[test]
+    fn renumber_fills_first_unused() {
+        // 9 is new, and 5 wants the slot 4 already has.
+        let mut raw = packed(&[9, 4, 5]);
+        let numbers: Numbers = vec![(uuid(4), 3), (uuid(5), 3)].into_iter().collect();
+        renumber(&mut raw, &numbers).unwrap();
+        let found = entry_numbers(&raw);
+        assert_eq!(found[&uuid(4)], 3);
+        assert_eq!(found[&uuid(9)], 1);
+        assert_eq!(found[&uuid(5)], 2);
+    }
+}
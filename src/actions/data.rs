@@ -0,0 +1,287 @@
+//! Partition data handling, interface agnostic.
+use anyhow::{anyhow, Result};
+use std::{
+    fs, io,
+    io::{prelude::*, SeekFrom},
+};
+use structopt::clap::arg_enum;
+use tracing::info;
+
+arg_enum! {
+    /// Supported hash algorithms for [`hash`].
+    #[derive(Debug, Copy, Clone)]
+    pub enum Algorithm {
+        Sha256,
+        Blake3,
+    }
+}
+
+/// Size of the buffer used when copying data, in bytes.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Size of the holes [`copy_sparse`] can leave, in bytes.
+const SPARSE_SIZE: usize = 4096;
+
+/// Copy `len` bytes at `src` to `dest`, within `f`.
+///
+/// The two ranges may overlap. When `dest` is after `src` the copy runs
+/// backwards, from the end, so nothing is overwritten before it was copied.
+pub fn copy_within<F: Read + Write + Seek>(f: &mut F, src: u64, dest: u64, len: u64) -> Result<()> {
+    info!(src, dest, len, "Copying data");
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(CHUNK_SIZE);
+        // Offset of this chunk from the start of both ranges.
+        let off = if dest > src { len - done - chunk } else { done };
+        let buf = &mut buf[..chunk as usize];
+        f.seek(SeekFrom::Start(src + off))?;
+        f.read_exact(buf)?;
+        f.seek(SeekFrom::Start(dest + off))?;
+        f.write_all(buf)?;
+        done += chunk;
+    }
+    f.flush()?;
+    Ok(())
+}
+
+/// Write `len` zero bytes at `offset` in `dest`.
+pub fn zero<W: Write + Seek>(dest: &mut W, offset: u64, len: u64) -> Result<()> {
+    info!(offset, len, "Zeroing data");
+    let buf = vec![0; len.min(CHUNK_SIZE) as usize];
+    dest.seek(SeekFrom::Start(offset))?;
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(CHUNK_SIZE);
+        dest.write_all(&buf[..chunk as usize])?;
+        done += chunk;
+    }
+    dest.flush()?;
+    Ok(())
+}
+
+/// Copy the `len` bytes at `offset` in `source` to the start of `dest`.
+///
+/// Data that reads as all zeros is skipped instead of written, leaving holes
+/// in `dest`.
+pub fn copy_sparse<R: Read + Seek>(
+    source: &mut R,
+    offset: u64,
+    len: u64,
+    dest: &mut fs::File,
+) -> Result<()> {
+    info!(offset, len, "Copying data");
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    source.seek(SeekFrom::Start(offset))?;
+    dest.seek(SeekFrom::Start(0))?;
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(CHUNK_SIZE);
+        let buf = &mut buf[..chunk as usize];
+        source.read_exact(buf)?;
+        for piece in buf.chunks(SPARSE_SIZE) {
+            if piece.iter().all(|b| *b == 0) {
+                dest.seek(SeekFrom::Current(piece.len() as i64))?;
+            } else {
+                dest.write_all(piece)?;
+            }
+        }
+        done += chunk;
+    }
+    // Skipped zeros at the end still need to count.
+    dest.set_len(len)?;
+    dest.flush()?;
+    Ok(())
+}
+
+/// Read from `source` until `buf` is full or there is no more data.
+///
+/// Returns how much was read.
+fn read_full<R: Read>(source: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match source.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Write all of `source` to `dest` at `offset`, failing if there's more than
+/// `len` bytes.
+///
+/// Since `source` may be a stream, anything before the limit has already been
+/// written when this fails.
+///
+/// Returns how many bytes were written, and their CRC32.
+pub fn write_limited<R: Read, W: Write + Seek>(
+    source: &mut R,
+    dest: &mut W,
+    offset: u64,
+    len: u64,
+) -> Result<(u64, u32)> {
+    info!(offset, len, "Writing data");
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut crc = crc32fast::Hasher::new();
+    dest.seek(SeekFrom::Start(offset))?;
+    let mut done = 0;
+    loop {
+        let read = read_full(source, &mut buf)?;
+        if read == 0 {
+            break;
+        }
+        if done + read as u64 > len {
+            return Err(anyhow!("Input is larger than the {} bytes available", len));
+        }
+        dest.write_all(&buf[..read])?;
+        crc.update(&buf[..read]);
+        done += read as u64;
+    }
+    dest.flush()?;
+    Ok((done, crc.finalize()))
+}
+
+/// Check the `len` bytes at `offset` in `source` have the CRC32 `expected`.
+pub fn verify_crc<R: Read + Seek>(
+    source: &mut R,
+    offset: u64,
+    len: u64,
+    expected: u32,
+) -> Result<()> {
+    info!(offset, len, "Verifying data");
+    let mut crc = crc32fast::Hasher::new();
+    read_chunks(source, offset, len, |buf| crc.update(buf))?;
+    let found = crc.finalize();
+    if found != expected {
+        return Err(anyhow!(
+            "Verification failed, read back CRC32 {:#010x} but wrote {:#010x}",
+            found,
+            expected
+        ));
+    }
+    Ok(())
+}
+
+/// Drop any cached data for the `len` bytes at `offset` in `f`, so reading it
+/// again comes from the device.
+///
+/// Only data already written to the device can be dropped, so sync `f` first.
+pub fn drop_cache(f: &fs::File, offset: u64, len: u64) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // Safety: `f` is an open file descriptor for the whole call.
+    let ret = unsafe {
+        libc::posix_fadvise(
+            f.as_raw_fd(),
+            offset as libc::off_t,
+            len as libc::off_t,
+            libc::POSIX_FADV_DONTNEED,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret).into());
+    }
+    Ok(())
+}
+
+/// Hash the `len` bytes at `offset` in `source` with `algorithm`.
+///
+/// Returns the hash as lowercase hex.
+pub fn hash<R: Read + Seek>(
+    source: &mut R,
+    offset: u64,
+    len: u64,
+    algorithm: Algorithm,
+) -> Result<String> {
+    info!(offset, len, %algorithm, "Hashing data");
+    Ok(match algorithm {
+        Algorithm::Sha256 => {
+            use sha2::Digest;
+
+            let mut sha256 = sha2::Sha256::new();
+            read_chunks(source, offset, len, |buf| sha256.update(buf))?;
+            sha256
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect()
+        }
+        Algorithm::Blake3 => {
+            let mut blake3 = blake3::Hasher::new();
+            read_chunks(source, offset, len, |buf| {
+                blake3.update(buf);
+            })?;
+            blake3.finalize().to_hex().to_string()
+        }
+    })
+}
+
+/// Read the `len` bytes at `offset` in `source`, passing each chunk to `f`.
+fn read_chunks<R: Read + Seek, F: FnMut(&[u8])>(
+    source: &mut R,
+    offset: u64,
+    len: u64,
+    mut f: F,
+) -> Result<()> {
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    source.seek(SeekFrom::Start(offset))?;
+    let mut done = 0;
+    while done < len {
+        let chunk = (len - done).min(CHUNK_SIZE);
+        let buf = &mut buf[..chunk as usize];
+        source.read_exact(buf)?;
+        f(&*buf);
+        done += chunk;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Data that differs at every offset for a while, so misplaced chunks show.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Check [`copy_within`] matches [`slice::copy_within`].
+    fn check_copy(src: u64, dest: u64, len: u64) {
+        let data = pattern((CHUNK_SIZE * 4) as usize);
+        let mut expected = data.clone();
+        expected.copy_within(src as usize..(src + len) as usize, dest as usize);
+        let mut f = Cursor::new(data);
+        copy_within(&mut f, src, dest, len).unwrap();
+        assert!(
+            f.into_inner() == expected,
+            "{} to {}, {} bytes",
+            src,
+            dest,
+            len
+        );
+    }
+
+    #[test]
+    fn copy_within_forward_overlap() {
+        let len = CHUNK_SIZE * 2 + 12345;
+        check_copy(100, 100 + CHUNK_SIZE / 2, len);
+        check_copy(0, 1, len);
+    }
+
+    #[test]
+    fn copy_within_backward_overlap() {
+        let len = CHUNK_SIZE * 2 + 12345;
+        check_copy(100 + CHUNK_SIZE / 2, 100, len);
+        check_copy(1, 0, len);
+    }
+
+    #[test]
+    fn copy_within_no_overlap() {
+        check_copy(0, CHUNK_SIZE * 2, CHUNK_SIZE + 1);
+        check_copy(CHUNK_SIZE * 2, 0, CHUNK_SIZE + 1);
+    }
+}
@@ -0,0 +1,494 @@
+//! Raw on-disk GPT structures.
+//!
+//! `parts` only exposes what it needs to build a valid Gpt, so things like
+//! partition attributes and the backup header are handled directly here.
+use anyhow::{anyhow, Result};
+use parts::{
+    types::{BlockSize, Size},
+    uuid::Uuid,
+};
+use serde::Serialize;
+use std::{
+    convert::TryInto,
+    io::{prelude::*, SeekFrom},
+};
+
+/// GPT header signature.
+pub const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// Size of the GPT header we understand, in bytes.
+pub const HEADER_SIZE: u32 = 92;
+
+/// Size of the partition entry we understand, in bytes.
+pub const ENTRY_SIZE: u32 = 128;
+
+/// Largest partition entry array we'll read, in bytes.
+///
+/// Anything bigger is assumed to be garbage.
+const MAX_ENTRY_ARRAY: u64 = 1024 * 1024;
+
+/// CRC32 as used by GPT.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(buf[off..off + 4].try_into().expect("Slice is 4 bytes"))
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(buf[off..off + 8].try_into().expect("Slice is 8 bytes"))
+}
+
+fn uuid_at(buf: &[u8], off: usize) -> Uuid {
+    Uuid::from_bytes_me(buf[off..off + 16].try_into().expect("Slice is 16 bytes"))
+}
+
+/// A GPT header.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Header {
+    pub revision: u32,
+    pub header_size: u32,
+    pub header_crc32: u32,
+    pub this_lba: u64,
+    pub alt_lba: u64,
+    pub first_usable: u64,
+    pub last_usable: u64,
+    pub disk_uuid: Uuid,
+    pub entries_lba: u64,
+    pub entries: u32,
+    pub entry_size: u32,
+    pub entries_crc32: u32,
+}
+
+impl Header {
+    /// Parse a header from `buf`.
+    ///
+    /// Fails if `buf` is too small or the signature is wrong.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf.len() < HEADER_SIZE as usize {
+            return Err(anyhow!("GPT header is too small"));
+        }
+        if &buf[..8] != SIGNATURE {
+            return Err(anyhow!("Invalid GPT header signature"));
+        }
+        Ok(Header {
+            revision: u32_at(buf, 8),
+            header_size: u32_at(buf, 12),
+            header_crc32: u32_at(buf, 16),
+            this_lba: u64_at(buf, 24),
+            alt_lba: u64_at(buf, 32),
+            first_usable: u64_at(buf, 40),
+            last_usable: u64_at(buf, 48),
+            disk_uuid: uuid_at(buf, 56),
+            entries_lba: u64_at(buf, 72),
+            entries: u32_at(buf, 80),
+            entry_size: u32_at(buf, 84),
+            entries_crc32: u32_at(buf, 88),
+        })
+    }
+
+    /// Serialize the header, as-is.
+    pub fn to_bytes(self) -> [u8; HEADER_SIZE as usize] {
+        let mut buf = [0; HEADER_SIZE as usize];
+        buf[..8].copy_from_slice(SIGNATURE);
+        buf[8..12].copy_from_slice(&self.revision.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.header_size.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.header_crc32.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.this_lba.to_le_bytes());
+        buf[32..40].copy_from_slice(&self.alt_lba.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.first_usable.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.last_usable.to_le_bytes());
+        buf[56..72].copy_from_slice(&self.disk_uuid.to_bytes_me());
+        buf[72..80].copy_from_slice(&self.entries_lba.to_le_bytes());
+        buf[80..84].copy_from_slice(&self.entries.to_le_bytes());
+        buf[84..88].copy_from_slice(&self.entry_size.to_le_bytes());
+        buf[88..92].copy_from_slice(&self.entries_crc32.to_le_bytes());
+        buf
+    }
+
+    /// Calculate what `header_crc32` should be.
+    ///
+    /// Anything past the fields we understand is treated as zero, as the
+    /// specification requires.
+    pub fn calculate_crc(&self) -> u32 {
+        let header = Header {
+            header_crc32: 0,
+            ..*self
+        };
+        let mut buf = header.to_bytes().to_vec();
+        buf.resize(
+            self.header_size.max(HEADER_SIZE).min(u16::MAX.into()) as usize,
+            0,
+        );
+        crc32(&buf)
+    }
+
+    /// Size of the partition entry array, in bytes.
+    pub fn entries_len(&self) -> u64 {
+        u64::from(self.entries) * u64::from(self.entry_size)
+    }
+}
+
+/// A GPT partition entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Entry {
+    pub partition_type: Uuid,
+    pub uuid: Uuid,
+    pub start: u64,
+    pub end: u64,
+    pub attributes: u64,
+    pub name: [u16; 36],
+}
+
+impl Default for Entry {
+    fn default() -> Self {
+        Entry {
+            partition_type: Uuid::nil(),
+            uuid: Uuid::nil(),
+            start: 0,
+            end: 0,
+            attributes: 0,
+            name: [0; 36],
+        }
+    }
+}
+
+impl Entry {
+    /// Parse an entry from `buf`, which must be at least [`ENTRY_SIZE`] bytes.
+    pub fn from_bytes(buf: &[u8]) -> Self {
+        let mut name = [0; 36];
+        for (i, c) in name.iter_mut().enumerate() {
+            *c = u16::from_le_bytes([buf[56 + i * 2], buf[57 + i * 2]]);
+        }
+        Entry {
+            partition_type: uuid_at(buf, 0),
+            uuid: uuid_at(buf, 16),
+            start: u64_at(buf, 32),
+            end: u64_at(buf, 40),
+            attributes: u64_at(buf, 48),
+            name,
+        }
+    }
+
+    /// Serialize the entry.
+    pub fn to_bytes(self) -> [u8; ENTRY_SIZE as usize] {
+        let mut buf = [0; ENTRY_SIZE as usize];
+        buf[..16].copy_from_slice(&self.partition_type.to_bytes_me());
+        buf[16..32].copy_from_slice(&self.uuid.to_bytes_me());
+        buf[32..40].copy_from_slice(&self.start.to_le_bytes());
+        buf[40..48].copy_from_slice(&self.end.to_le_bytes());
+        buf[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, c) in self.name.iter().enumerate() {
+            buf[56 + i * 2..58 + i * 2].copy_from_slice(&c.to_le_bytes());
+        }
+        buf
+    }
+
+    /// Whether this entry is in use.
+    pub fn is_used(&self) -> bool {
+        self.partition_type != Uuid::nil()
+    }
+
+    /// Partition name, up to the first nul.
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(36);
+        String::from_utf16_lossy(&self.name[..len])
+    }
+
+    /// Set the partition name.
+    ///
+    /// Fails if it's longer than 36 UTF-16 code units.
+    pub fn set_name(&mut self, name: &str) -> Result<()> {
+        let encoded: Vec<u16> = name.encode_utf16().collect();
+        if encoded.len() > self.name.len() {
+            return Err(anyhow!("Partition name {:?} is too long", name));
+        }
+        self.name = [0; 36];
+        self.name[..encoded.len()].copy_from_slice(&encoded);
+        Ok(())
+    }
+}
+
+/// One copy of the GPT, a header and its partition entry array.
+#[derive(Debug, Clone)]
+pub struct Table {
+    pub header: Header,
+    pub entries: Vec<Entry>,
+}
+
+impl Table {
+    /// Read the table whose header is at block `lba`.
+    ///
+    /// This does NOT check either CRC.
+    pub fn read<R: Read + Seek>(source: &mut R, lba: u64, block_size: BlockSize) -> Result<Self> {
+        let block_size = block_size.get();
+        let mut buf = vec![0; block_size as usize];
+        source.seek(SeekFrom::Start(lba * block_size))?;
+        source.read_exact(&mut buf)?;
+        let header = Header::from_bytes(&buf)?;
+        if header.entry_size < ENTRY_SIZE || header.entries_len() > MAX_ENTRY_ARRAY {
+            return Err(anyhow!(
+                "Invalid partition entry array of {} entries of {} bytes",
+                header.entries,
+                header.entry_size
+            ));
+        }
+        let mut buf = vec![0; header.entries_len() as usize];
+        source.seek(SeekFrom::Start(header.entries_lba * block_size))?;
+        source.read_exact(&mut buf)?;
+        let entries = buf
+            .chunks_exact(header.entry_size as usize)
+            .map(Entry::from_bytes)
+            .collect();
+        Ok(Table { header, entries })
+    }
+
+    /// The partition entry array, as it would be written to disk.
+    pub fn entries_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.header.entries_len() as usize);
+        for entry in &self.entries {
+            buf.extend_from_slice(&entry.to_bytes());
+            buf.resize(
+                buf.len() + (self.header.entry_size - ENTRY_SIZE) as usize,
+                0,
+            );
+        }
+        buf
+    }
+
+    /// Calculate what `entries_crc32` should be.
+    pub fn calculate_entries_crc(&self) -> u32 {
+        crc32(&self.entries_bytes())
+    }
+
+    /// Update both CRCs to match the current contents.
+    pub fn update_crc(&mut self) {
+        self.header.entries = self.entries.len() as u32;
+        self.header.entries_crc32 = self.calculate_entries_crc();
+        self.header.header_crc32 = self.header.calculate_crc();
+    }
+
+    /// Write the partition entry array and header to `dest`, updating both CRCs
+    /// first.
+    pub fn write<W: Write + Seek>(&mut self, dest: &mut W, block_size: BlockSize) -> Result<()> {
+        self.update_crc();
+        let block_size = block_size.get();
+        dest.seek(SeekFrom::Start(self.header.entries_lba * block_size))?;
+        dest.write_all(&self.entries_bytes())?;
+        let mut buf = self.header.to_bytes().to_vec();
+        buf.resize(block_size as usize, 0);
+        dest.seek(SeekFrom::Start(self.header.this_lba * block_size))?;
+        dest.write_all(&buf)?;
+        Ok(())
+    }
+}
+
+/// Update the size of the protective MBR partition to cover the whole device.
+///
+/// Does nothing if there is no protective MBR partition.
+pub fn update_protective_mbr<F: Read + Write + Seek>(
+    f: &mut F,
+    block_size: BlockSize,
+    disk_size: Size,
+) -> Result<()> {
+    let blocks = disk_size.as_bytes() / block_size.get();
+    let size = (blocks - 1).min(u32::MAX.into()) as u32;
+    let mut mbr = [0; 512];
+    f.seek(SeekFrom::Start(0))?;
+    f.read_exact(&mut mbr)?;
+    if let Some(record) = mbr[446..510]
+        .chunks_exact_mut(16)
+        .find(|record| record[4] == 0xEE)
+    {
+        record[12..16].copy_from_slice(&size.to_le_bytes());
+        f.seek(SeekFrom::Start(0))?;
+        f.write_all(&mbr)?;
+    }
+    Ok(())
+}
+
+/// Both copies of the GPT.
+#[derive(Debug, Clone)]
+pub struct RawGpt {
+    pub primary: Table,
+    pub backup: Table,
+}
+
+impl RawGpt {
+    /// Read both copies of the GPT.
+    ///
+    /// The backup is found through the primary header.
+    pub fn read<R: Read + Seek>(source: &mut R, block_size: BlockSize) -> Result<Self> {
+        let primary = Table::read(source, 1, block_size)?;
+        let backup = Table::read(source, primary.header.alt_lba, block_size)?;
+        Ok(RawGpt { primary, backup })
+    }
+
+    /// Index of the entry for partition `uuid`.
+    pub fn find(&self, uuid: Uuid) -> Result<usize> {
+        self.primary
+            .entries
+            .iter()
+            .position(|e| e.is_used() && e.uuid == uuid)
+            .ok_or_else(|| anyhow!("Couldn't find partition {}", uuid))
+    }
+
+    /// Write both copies of the GPT to `dest`.
+    ///
+    /// The primary copy is authoritative, its disk Uuid, usable range, and
+    /// partition entries are copied to the backup first.
+    pub fn write<W: Write + Seek>(&mut self, dest: &mut W, block_size: BlockSize) -> Result<()> {
+        self.backup.header.disk_uuid = self.primary.header.disk_uuid;
+        self.backup.header.first_usable = self.primary.header.first_usable;
+        self.backup.header.last_usable = self.primary.header.last_usable;
+        self.backup.header.entry_size = self.primary.header.entry_size;
+        self.backup.entries = self.primary.entries.clone();
+        self.backup.write(dest, block_size)?;
+        self.primary.write(dest, block_size)?;
+        dest.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Primary header of a 1 MiB disk with 512 byte blocks, made by an
+    /// independent GPT implementation.
+    const HEADER: &str = concat!(
+        "4546492050415254000001005c0000003bff13a1000000000100000000000000",
+        "ff070000000000002200000000000000de070000000000003c2d1e0f5a4b9746",
+        "88776655443322110200000000000000800000008000000051099e4d",
+    );
+
+    /// The only used entry on the same disk, the first one.
+    const ENTRY: &str = concat!(
+        "28732ac11ff8d211ba4b00a0c93ec93b3d2c1b6a5f4e71408293a4b5c6d7e8f9",
+        "4000000000000000ff0300000000000001000000000000804500460049002000",
+        "730079007300740065006d000000000000000000000000000000000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000000",
+    );
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn uuid(s: &str) -> Uuid {
+        s.parse().unwrap()
+    }
+
+    /// The known good table, with 128 entries.
+    fn table() -> Table {
+        let mut entries = vec![Entry::default(); 128];
+        entries[0] = Entry::from_bytes(&hex(ENTRY));
+        Table {
+            header: Header::from_bytes(&hex(HEADER)).unwrap(),
+            entries,
+        }
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let bytes = hex(HEADER);
+        let header = Header::from_bytes(&bytes).unwrap();
+        assert_eq!(header.revision, 0x0001_0000);
+        assert_eq!(header.header_size, HEADER_SIZE);
+        assert_eq!(header.header_crc32, 0xa113_ff3b);
+        assert_eq!(header.this_lba, 1);
+        assert_eq!(header.alt_lba, 2047);
+        assert_eq!(header.first_usable, 34);
+        assert_eq!(header.last_usable, 2014);
+        assert_eq!(
+            header.disk_uuid,
+            uuid("0F1E2D3C-4B5A-4697-8877-665544332211")
+        );
+        assert_eq!(header.entries_lba, 2);
+        assert_eq!(header.entries, 128);
+        assert_eq!(header.entry_size, ENTRY_SIZE);
+        assert_eq!(header.entries_crc32, 0x4d9e_0951);
+        assert_eq!(&header.to_bytes()[..], &bytes[..]);
+    }
+
+    #[test]
+    fn header_errors() {
+        let bytes = hex(HEADER);
+        assert!(Header::from_bytes(&bytes[..91]).is_err());
+        let mut bad = bytes;
+        bad[0] = b'X';
+        assert!(Header::from_bytes(&bad).is_err());
+    }
+
+    #[test]
+    fn entry_round_trip() {
+        let bytes = hex(ENTRY);
+        let entry = Entry::from_bytes(&bytes);
+        assert_eq!(
+            entry.partition_type,
+            uuid("C12A7328-F81F-11D2-BA4B-00A0C93EC93B")
+        );
+        assert_eq!(entry.uuid, uuid("6A1B2C3D-4E5F-4071-8293-A4B5C6D7E8F9"));
+        assert_eq!(entry.start, 64);
+        assert_eq!(entry.end, 1023);
+        assert_eq!(entry.attributes, 1 << 63 | 1);
+        assert_eq!(entry.name(), "EFI system");
+        assert!(entry.is_used());
+        assert_eq!(&entry.to_bytes()[..], &bytes[..]);
+        assert!(!Entry::from_bytes(&[0; ENTRY_SIZE as usize]).is_used());
+    }
+
+    #[test]
+    fn entry_name() {
+        let mut entry = Entry::default();
+        entry.set_name("Grüße ☃").unwrap();
+        assert_eq!(entry.name(), "Grüße ☃");
+        assert_eq!(Entry::from_bytes(&entry.to_bytes()).name(), "Grüße ☃");
+        entry.set_name(&"x".repeat(36)).unwrap();
+        assert_eq!(entry.name(), "x".repeat(36));
+        assert!(entry.set_name(&"x".repeat(37)).is_err());
+    }
+
+    #[test]
+    fn known_good_crcs() {
+        let table = table();
+        assert_eq!(table.header.calculate_crc(), table.header.header_crc32);
+        assert_eq!(table.calculate_entries_crc(), table.header.entries_crc32);
+    }
+
+    #[test]
+    fn crc_covers_header_size() {
+        let mut header = table().header;
+        header.header_size = 100;
+        let mut bytes = Header {
+            header_crc32: 0,
+            ..header
+        }
+        .to_bytes()
+        .to_vec();
+        bytes.resize(100, 0);
+        assert_eq!(header.calculate_crc(), crc32(&bytes));
+        assert_ne!(header.calculate_crc(), table().header.header_crc32);
+    }
+
+    #[test]
+    fn table_write_read() {
+        let block_size = BlockSize::new(512);
+        let mut expected = table();
+        expected.entries[5].start = 1024;
+        expected.entries[5].end = 2014;
+        expected.entries[5].partition_type = expected.entries[0].partition_type;
+        let mut f = Cursor::new(vec![0; 2048 * 512]);
+        expected.write(&mut f, block_size).unwrap();
+        let table = Table::read(&mut f, 1, block_size).unwrap();
+        assert_eq!(table.header, expected.header);
+        assert!(table.entries == expected.entries);
+        assert_eq!(table.header.calculate_crc(), table.header.header_crc32);
+        assert_eq!(table.calculate_entries_crc(), table.header.entries_crc32);
+        assert_ne!(table.header.entries_crc32, 0x4d9e_0951);
+    }
+}
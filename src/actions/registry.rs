@@ -0,0 +1,240 @@
+//! Partition type registry, interface agnostic.
+use anyhow::{anyhow, Context, Result};
+use parts::uuid::Uuid;
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+use tracing::{debug, info};
+
+/// Well known partition types, as Uuid, name, and aliases.
+const BUILTIN: &[(&str, &str, &[&str])] = &[
+    (
+        "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+        "EFI System",
+        &["esp", "efi"],
+    ),
+    (
+        "21686148-6449-6E6F-744E-656564454649",
+        "BIOS boot",
+        &["bios-boot"],
+    ),
+    (
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172",
+        "Extended boot loader",
+        &["xbootldr"],
+    ),
+    (
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+        "Linux filesystem",
+        &["linux", "linux-fs"],
+    ),
+    (
+        "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F",
+        "Linux swap",
+        &["linux-swap", "swap"],
+    ),
+    (
+        "933AC7E1-2EB4-4F13-B844-0E14E2AEF915",
+        "Linux home",
+        &["linux-home", "home"],
+    ),
+    (
+        "3B8F8425-20E0-4F3B-907F-1A25A76F98E8",
+        "Linux server data",
+        &["linux-srv", "srv"],
+    ),
+    (
+        "44479540-F297-41B2-9AF7-D131D5F0458A",
+        "Linux root (x86)",
+        &["linux-root-x86"],
+    ),
+    (
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+        "Linux root (x86-64)",
+        &["linux-root-x86-64"],
+    ),
+    (
+        "69DAD710-2CE4-4E3C-B16C-21A1D49ABED3",
+        "Linux root (ARM)",
+        &["linux-root-arm"],
+    ),
+    (
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE",
+        "Linux root (ARM64)",
+        &["linux-root-arm64"],
+    ),
+    (
+        "8484680C-9521-48C6-9C11-B0720656F69E",
+        "Linux /usr (x86-64)",
+        &["linux-usr-x86-64"],
+    ),
+    (
+        "B0E01050-EE5F-4390-949A-9101B17104E9",
+        "Linux /usr (ARM64)",
+        &["linux-usr-arm64"],
+    ),
+    (
+        "E6D6D379-F507-44C2-A23C-238F2A3DF928",
+        "Linux LVM",
+        &["linux-lvm", "lvm"],
+    ),
+    (
+        "A19D880F-05FC-4D3B-A006-743F0F84911E",
+        "Linux RAID",
+        &["linux-raid", "raid"],
+    ),
+    (
+        "CA7D7CCB-63ED-4C53-861C-1742536059CC",
+        "Linux LUKS",
+        &["linux-luks", "luks"],
+    ),
+    (
+        "7FFEC5C9-2D00-49B7-8941-3EA10A5586B7",
+        "Linux dm-crypt",
+        &["linux-dmcrypt"],
+    ),
+    (
+        "8DA63339-0007-60C0-C436-083AC8230908",
+        "Linux reserved",
+        &["linux-reserved"],
+    ),
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        "Microsoft basic data",
+        &["msft-basic"],
+    ),
+    (
+        "E3C9E316-0B5C-4DB8-817D-F92DF00215AE",
+        "Microsoft reserved",
+        &["msft-reserved"],
+    ),
+    (
+        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC",
+        "Windows recovery",
+        &["windows-recovery"],
+    ),
+    (
+        "48465300-0000-11AA-AA11-00306543ECAC",
+        "Apple HFS+",
+        &["apple-hfs"],
+    ),
+    (
+        "7C3457EF-0000-11AA-AA11-00306543ECAC",
+        "Apple APFS",
+        &["apple-apfs"],
+    ),
+    (
+        "516E7CB6-6ECF-11D6-8FF8-00022D09712B",
+        "FreeBSD UFS",
+        &["freebsd-ufs"],
+    ),
+    (
+        "FE3A2A5D-4F32-41A7-B725-ACCC3285A309",
+        "ChromeOS kernel",
+        &["chromeos-kernel"],
+    ),
+];
+
+/// A known partition type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypeInfo {
+    pub uuid: Uuid,
+
+    pub name: String,
+
+    /// Short names accepted in place of the Uuid.
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+/// Known partition types, by Uuid, name, and alias.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    types: Vec<TypeInfo>,
+}
+
+impl Registry {
+    /// Only the built in types.
+    pub fn builtin() -> Self {
+        let types = BUILTIN
+            .iter()
+            .map(|(uuid, name, aliases)| TypeInfo {
+                uuid: uuid.parse().expect("Built in type Uuid is invalid"),
+                name: (*name).into(),
+                aliases: aliases.iter().map(|a| (*a).into()).collect(),
+            })
+            .collect();
+        Self { types }
+    }
+
+    /// The built in types, extended by the user file at [`user_path`], if it
+    /// exists.
+    ///
+    /// The file is a JSON array of [`TypeInfo`].
+    /// Entries with the same Uuid as a built in type replace it.
+    pub fn load() -> Result<Self> {
+        let mut registry = Self::builtin();
+        let path = match user_path() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(registry),
+        };
+        info!(path = %path.display(), "Loading partition types");
+        let user: Vec<TypeInfo> = serde_json::from_slice(
+            &fs::read(&path).with_context(|| format!("Couldn't read {}", path.display()))?,
+        )
+        .with_context(|| format!("Invalid partition types in {}", path.display()))?;
+        for ty in user {
+            match registry.types.iter_mut().find(|t| t.uuid == ty.uuid) {
+                Some(existing) => *existing = ty,
+                None => registry.types.push(ty),
+            }
+        }
+        Ok(registry)
+    }
+
+    /// All known types.
+    pub fn types(&self) -> &[TypeInfo] {
+        &self.types
+    }
+
+    /// Resolve `s`, a type Uuid, alias, or name, to a Uuid.
+    ///
+    /// Aliases and names are case insensitive.
+    pub fn lookup(&self, s: &str) -> Result<Uuid> {
+        if let Ok(uuid) = s.parse() {
+            return Ok(uuid);
+        }
+        self.types
+            .iter()
+            .find(|t| {
+                t.name.eq_ignore_ascii_case(s)
+                    || t.aliases.iter().any(|a| a.eq_ignore_ascii_case(s))
+            })
+            .map(|t| t.uuid)
+            .ok_or_else(|| anyhow!("Unknown partition type {}", s))
+    }
+
+    /// Name of the type `uuid`, if known.
+    pub fn name(&self, uuid: Uuid) -> Option<&str> {
+        self.types
+            .iter()
+            .find(|t| t.uuid == uuid)
+            .map(|t| t.name.as_str())
+    }
+
+    /// Name of the type `uuid`, or the Uuid itself if unknown.
+    pub fn describe(&self, uuid: Uuid) -> String {
+        self.name(uuid).map_or_else(|| uuid.to_string(), Into::into)
+    }
+}
+
+/// Path to the user partition type file.
+///
+/// `$XDG_CONFIG_HOME/parts/types.json`, or `~/.config/parts/types.json`.
+pub fn user_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    debug!(?dir, "Config directory");
+    dir.map(|dir| dir.join("parts").join("types.json"))
+}
@@ -0,0 +1,654 @@
+//! Gpt integrity checks, interface agnostic.
+use super::raw::{Header, Table, HEADER_SIZE};
+use crate::Info;
+use anyhow::Result;
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{prelude::*, SeekFrom},
+};
+use structopt::clap::arg_enum;
+
+arg_enum! {
+    /// Which copy of the Gpt.
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum Location {
+        Primary,
+        Backup,
+    }
+}
+
+/// A problem found with the Gpt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The MBR boot signature is missing.
+    MbrSignature,
+
+    /// There is no protective MBR partition.
+    MbrNotProtective,
+
+    /// The protective MBR partition doesn't cover the disk.
+    MbrProtectiveRange {
+        start: u32,
+        size: u32,
+        expected_size: u32,
+    },
+
+    /// The device is too small to hold a Gpt.
+    TooSmall { size: u64 },
+
+    /// A copy of the Gpt couldn't be read at all.
+    Unreadable { copy: Location, error: String },
+
+    /// The backup Gpt isn't at the end of the device, such as after it grew.
+    BackupNotAtEnd { lba: u64, last_lba: u64 },
+
+    /// A header field has a bad value.
+    BadField {
+        copy: Location,
+        field: &'static str,
+        expected: u64,
+        found: u64,
+    },
+
+    /// A header CRC is wrong.
+    HeaderCrc {
+        copy: Location,
+        expected: u32,
+        found: u32,
+    },
+
+    /// A partition entry array CRC is wrong.
+    EntriesCrc {
+        copy: Location,
+        expected: u32,
+        found: u32,
+    },
+
+    /// A field differs between the two copies.
+    Mismatch { field: &'static str },
+
+    /// A partition ends before it starts.
+    Backwards { number: usize },
+
+    /// A partition is outside the usable range.
+    OutsideUsable { number: usize, start: u64, end: u64 },
+
+    /// Two partitions overlap.
+    Overlap { first: usize, second: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::MbrSignature => write!(f, "Protective MBR is missing its boot signature"),
+            Problem::MbrNotProtective => write!(f, "MBR has no protective (0xEE) partition"),
+            Problem::MbrProtectiveRange {
+                start,
+                size,
+                expected_size,
+            } => write!(
+                f,
+                "Protective MBR partition starts at block {} with {} blocks, expected 1 with {}",
+                start, size, expected_size
+            ),
+            Problem::TooSmall { size } => {
+                write!(f, "Device is {} bytes, too small to hold a Gpt", size)
+            }
+            Problem::Unreadable { copy, error } => {
+                write!(f, "{} Gpt couldn't be read: {}", copy, error)
+            }
+            Problem::BackupNotAtEnd { lba, last_lba } => write!(
+                f,
+                "Backup Gpt is at LBA {}, not the last LBA {}",
+                lba, last_lba
+            ),
+            Problem::BadField {
+                copy,
+                field,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} header {} is {}, expected {}",
+                copy, field, found, expected
+            ),
+            Problem::HeaderCrc {
+                copy,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} header CRC is {:#010x}, expected {:#010x}",
+                copy, found, expected
+            ),
+            Problem::EntriesCrc {
+                copy,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} partition entry array CRC is {:#010x}, expected {:#010x}",
+                copy, found, expected
+            ),
+            Problem::Mismatch { field } => {
+                write!(f, "Primary and backup Gpt have different {}", field)
+            }
+            Problem::Backwards { number } => {
+                write!(f, "Partition {} ends before it starts", number)
+            }
+            Problem::OutsideUsable { number, start, end } => write!(
+                f,
+                "Partition {} is outside the usable blocks {} to {}",
+                number, start, end
+            ),
+            Problem::Overlap { first, second } => {
+                write!(f, "Partitions {} and {} overlap", first, second)
+            }
+        }
+    }
+}
+
+/// Check the protective MBR in `source`.
+fn check_mbr<R: Read + Seek>(source: &mut R, info: &Info) -> Result<Vec<Problem>> {
+    let mut mbr = [0; 512];
+    source.seek(SeekFrom::Start(0))?;
+    source.read_exact(&mut mbr)?;
+    let mut problems = Vec::new();
+    if mbr[510..] != [0x55, 0xAA] {
+        problems.push(Problem::MbrSignature);
+    }
+    let blocks = info.disk_size.as_bytes() / info.block_size.get();
+    let expected_size = (blocks - 1).min(u32::MAX.into()) as u32;
+    match mbr[446..510]
+        .chunks_exact(16)
+        .find(|record| record[4] == 0xEE)
+    {
+        Some(record) => {
+            let start = u32::from_le_bytes(record[8..12].try_into().expect("Slice is 4 bytes"));
+            let size = u32::from_le_bytes(record[12..16].try_into().expect("Slice is 4 bytes"));
+            if start != 1 || size != expected_size {
+                problems.push(Problem::MbrProtectiveRange {
+                    start,
+                    size,
+                    expected_size,
+                });
+            }
+        }
+        None => problems.push(Problem::MbrNotProtective),
+    }
+    Ok(problems)
+}
+
+/// Check a single copy of the Gpt, found at `lba`, on its own.
+///
+/// `alt_lba` is where the other copy should be.
+///
+/// The partition entry arrays and usable blocks must be laid out in order,
+/// between the headers, assuming both entry arrays are the same size.
+pub fn check_table(
+    table: &Table,
+    info: &Info,
+    copy: Location,
+    lba: u64,
+    alt_lba: u64,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let header = &table.header;
+    let mut field = |field, expected: u64, found: u64| {
+        if expected != found {
+            problems.push(Problem::BadField {
+                copy,
+                field,
+                expected,
+                found,
+            });
+        }
+    };
+    field("revision", 0x0001_0000, header.revision.into());
+    if header.header_size < HEADER_SIZE {
+        field("header size", HEADER_SIZE.into(), header.header_size.into());
+    }
+    field("current LBA", lba, header.this_lba);
+    field("backup LBA", alt_lba, header.alt_lba);
+    // `expected` is the limit that was crossed.
+    let mut bound = |field, ok: bool, expected: u64, found: u64| {
+        if !ok {
+            problems.push(Problem::BadField {
+                copy,
+                field,
+                expected,
+                found,
+            });
+        }
+    };
+    let blocks = header.entries_len().div_ceil(info.block_size.get());
+    let (first, last, entries) = (header.first_usable, header.last_usable, header.entries_lba);
+    match copy {
+        Location::Primary => {
+            bound("partition entries LBA", entries > lba, lba + 1, entries);
+            let end = entries.saturating_add(blocks);
+            bound("first usable LBA", first >= end, end, first);
+            let limit = alt_lba.saturating_sub(blocks + 1);
+            bound("last usable LBA", last <= limit, limit, last);
+        }
+        Location::Backup => {
+            let end = alt_lba.saturating_add(1 + blocks);
+            bound("first usable LBA", first >= end, end, first);
+            let limit = lba.saturating_sub(blocks);
+            bound("partition entries LBA", entries <= limit, limit, entries);
+            let limit = entries.saturating_sub(1);
+            bound("last usable LBA", last <= limit, limit, last);
+        }
+    }
+    problems.extend(check_crcs(table, copy));
+    problems
+}
+
+/// Check both CRCs of a single copy of the Gpt.
+///
+/// A copy with CRC problems can't be trusted.
+pub fn check_crcs(table: &Table, copy: Location) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let header = &table.header;
+    let expected = header.calculate_crc();
+    if expected != header.header_crc32 {
+        problems.push(Problem::HeaderCrc {
+            copy,
+            expected,
+            found: header.header_crc32,
+        });
+    }
+    let expected = table.calculate_entries_crc();
+    if expected != header.entries_crc32 {
+        problems.push(Problem::EntriesCrc {
+            copy,
+            expected,
+            found: header.entries_crc32,
+        });
+    }
+    problems
+}
+
+/// Check the partitions in `table` fit in the usable range and don't overlap.
+fn check_partitions(table: &Table) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let first_usable = table.header.first_usable;
+    let last_usable = table.header.last_usable;
+    let used: Vec<_> = table
+        .entries
+        .iter()
+        .enumerate()
+        .filter(|(_, e)| e.is_used())
+        .map(|(i, e)| (i + 1, e))
+        .collect();
+    for (number, entry) in &used {
+        if entry.start > entry.end {
+            problems.push(Problem::Backwards { number: *number });
+        } else if entry.start < first_usable || entry.end > last_usable {
+            problems.push(Problem::OutsideUsable {
+                number: *number,
+                start: first_usable,
+                end: last_usable,
+            });
+        }
+    }
+    for (i, (first, a)) in used.iter().enumerate() {
+        for (second, b) in &used[i + 1..] {
+            if a.start <= b.end && b.start <= a.end {
+                problems.push(Problem::Overlap {
+                    first: *first,
+                    second: *second,
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Check the primary and backup Gpt agree with each other.
+fn check_match(primary: &Table, backup: &Table) -> Vec<Problem> {
+    let (p, b) = (&primary.header, &backup.header);
+    let mut problems = Vec::new();
+    let mut field = |field, same: bool| {
+        if !same {
+            problems.push(Problem::Mismatch { field });
+        }
+    };
+    field("disk Uuids", p.disk_uuid == b.disk_uuid);
+    field("first usable LBAs", p.first_usable == b.first_usable);
+    field("last usable LBAs", p.last_usable == b.last_usable);
+    field("entry counts", p.entries == b.entries);
+    field("entry sizes", p.entry_size == b.entry_size);
+    field("partition entries", primary.entries == backup.entries);
+    problems
+}
+
+/// Read the backup Gpt from `source`.
+///
+/// It's read from where the `primary` header says it is, if there is one,
+/// otherwise, or if that fails, from the last block of the device.
+///
+/// Returns the block it was read from, or last tried, and the table.
+pub fn read_backup<R: Read + Seek>(
+    source: &mut R,
+    info: &Info,
+    primary: Option<&Header>,
+) -> (u64, Result<Table>) {
+    let last_lba = info.disk_size.as_bytes() / info.block_size.get() - 1;
+    if let Some(alt_lba) = primary.map(|h| h.alt_lba).filter(|lba| *lba != last_lba) {
+        if let Ok(table) = Table::read(source, alt_lba, info.block_size) {
+            return (alt_lba, Ok(table));
+        }
+    }
+    (last_lba, Table::read(source, last_lba, info.block_size))
+}
+
+/// Check the Gpt in `source` in detail, returning every problem found.
+pub fn verify<R: Read + Seek>(mut source: R, info: &Info) -> Result<Vec<Problem>> {
+    let size = info.disk_size.as_bytes();
+    // Protective MBR, and at least both headers.
+    if size / info.block_size.get() < 3 {
+        return Ok(vec![Problem::TooSmall { size }]);
+    }
+    let last_lba = size / info.block_size.get() - 1;
+    let mut problems = check_mbr(&mut source, info)?;
+    let primary = Table::read(&mut source, 1, info.block_size);
+    let (backup_lba, backup) =
+        read_backup(&mut source, info, primary.as_ref().ok().map(|t| &t.header));
+    let primary = match primary {
+        Ok(table) => {
+            // Where the backup is is checked separately, below.
+            let alt_lba = if backup.is_ok() { backup_lba } else { last_lba };
+            problems.extend(check_table(&table, info, Location::Primary, 1, alt_lba));
+            Some(table)
+        }
+        Err(e) => {
+            problems.push(Problem::Unreadable {
+                copy: Location::Primary,
+                error: e.to_string(),
+            });
+            None
+        }
+    };
+    let backup = match backup {
+        Ok(table) => {
+            if backup_lba != last_lba {
+                problems.push(Problem::BackupNotAtEnd {
+                    lba: backup_lba,
+                    last_lba,
+                });
+            }
+            problems.extend(check_table(&table, info, Location::Backup, backup_lba, 1));
+            Some(table)
+        }
+        Err(e) => {
+            problems.push(Problem::Unreadable {
+                copy: Location::Backup,
+                error: e.to_string(),
+            });
+            None
+        }
+    };
+    match (&primary, &backup) {
+        (Some(primary), Some(backup)) => {
+            problems.extend(check_partitions(primary));
+            problems.extend(check_match(primary, backup));
+        }
+        (Some(table), None) | (None, Some(table)) => problems.extend(check_partitions(table)),
+        (None, None) => (),
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::raw::{Entry, ENTRY_SIZE};
+    use parts::{
+        types::{BlockSize, Size},
+        uuid::Uuid,
+    };
+    use std::io::Cursor;
+
+    /// A 1 MiB disk with 512 byte blocks.
+    fn info() -> Info {
+        Info {
+            path: "test.img".into(),
+            block_size: BlockSize::new(512),
+            disk_size: Size::from_bytes(2048 * 512),
+            model: String::new(),
+            name: "test.img".into(),
+            alignment: 1024 * 1024,
+            alignment_offset: 0,
+        }
+    }
+
+    fn uuid(n: u64) -> Uuid {
+        format!("00000000-0000-4000-8000-{:012}", n)
+            .parse()
+            .unwrap()
+    }
+
+    fn entry(n: u64, start: u64, end: u64) -> Entry {
+        Entry {
+            partition_type: uuid(0),
+            uuid: uuid(n),
+            start,
+            end,
+            ..Entry::default()
+        }
+    }
+
+    /// Update both CRCs of `table`.
+    fn fix_crcs(table: &mut Table) {
+        table.header.entries_crc32 = table.calculate_entries_crc();
+        table.header.header_crc32 = table.header.calculate_crc();
+    }
+
+    /// A valid copy of the Gpt for [`info`], with `entries`.
+    fn table(copy: Location, entries: &[Entry]) -> Table {
+        let (this_lba, alt_lba, entries_lba) = match copy {
+            Location::Primary => (1, 2047, 2),
+            Location::Backup => (2047, 1, 2015),
+        };
+        let mut table = Table {
+            header: Header {
+                revision: 0x0001_0000,
+                header_size: HEADER_SIZE,
+                header_crc32: 0,
+                this_lba,
+                alt_lba,
+                first_usable: 34,
+                last_usable: 2014,
+                disk_uuid: uuid(1),
+                entries_lba,
+                entries: 128,
+                entry_size: ENTRY_SIZE,
+                entries_crc32: 0,
+            },
+            entries: vec![Entry::default(); 128],
+        };
+        table.entries[..entries.len()].copy_from_slice(entries);
+        fix_crcs(&mut table);
+        table
+    }
+
+    fn bad_field(copy: Location, field: &'static str, expected: u64, found: u64) -> Problem {
+        Problem::BadField {
+            copy,
+            field,
+            expected,
+            found,
+        }
+    }
+
+    /// A protective MBR for [`info`], with the protective partition `size`.
+    fn mbr(size: u32) -> Vec<u8> {
+        let mut mbr = vec![0; 2048 * 512];
+        mbr[446 + 4] = 0xEE;
+        mbr[446 + 8..446 + 12].copy_from_slice(&1u32.to_le_bytes());
+        mbr[446 + 12..446 + 16].copy_from_slice(&size.to_le_bytes());
+        mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+        mbr
+    }
+
+    #[test]
+    fn valid() {
+        let info = info();
+        let parts = [entry(2, 34, 1000), entry(3, 1001, 2014)];
+        let primary = table(Location::Primary, &parts);
+        let backup = table(Location::Backup, &parts);
+        assert_eq!(
+            check_table(&primary, &info, Location::Primary, 1, 2047),
+            vec![]
+        );
+        assert_eq!(
+            check_table(&backup, &info, Location::Backup, 2047, 1),
+            vec![]
+        );
+        assert_eq!(check_partitions(&primary), vec![]);
+        assert_eq!(check_match(&primary, &backup), vec![]);
+        let mut mbr = Cursor::new(mbr(2047));
+        assert_eq!(check_mbr(&mut mbr, &info).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn primary_layout() {
+        let info = info();
+        let check = |f: fn(&mut Header)| {
+            let mut table = table(Location::Primary, &[]);
+            f(&mut table.header);
+            fix_crcs(&mut table);
+            check_table(&table, &info, Location::Primary, 1, 2047)
+        };
+        assert_eq!(
+            check(|h| h.entries_lba = 1),
+            vec![bad_field(Location::Primary, "partition entries LBA", 2, 1)]
+        );
+        assert_eq!(
+            check(|h| h.first_usable = 33),
+            vec![bad_field(Location::Primary, "first usable LBA", 34, 33)]
+        );
+        assert_eq!(
+            check(|h| h.last_usable = 2015),
+            vec![bad_field(Location::Primary, "last usable LBA", 2014, 2015)]
+        );
+    }
+
+    #[test]
+    fn backup_layout() {
+        let info = info();
+        let check = |f: fn(&mut Header)| {
+            let mut table = table(Location::Backup, &[]);
+            f(&mut table.header);
+            fix_crcs(&mut table);
+            check_table(&table, &info, Location::Backup, 2047, 1)
+        };
+        assert_eq!(
+            check(|h| h.entries_lba = 2016),
+            vec![bad_field(
+                Location::Backup,
+                "partition entries LBA",
+                2015,
+                2016
+            )]
+        );
+        assert_eq!(
+            check(|h| h.last_usable = 2015),
+            vec![bad_field(Location::Backup, "last usable LBA", 2014, 2015)]
+        );
+        assert_eq!(
+            check(|h| h.first_usable = 33),
+            vec![bad_field(Location::Backup, "first usable LBA", 34, 33)]
+        );
+    }
+
+    #[test]
+    fn partitions() {
+        let table = table(
+            Location::Primary,
+            &[
+                entry(2, 34, 1000),
+                entry(3, 1000, 1500),
+                entry(4, 1800, 1700),
+                entry(5, 1900, 2015),
+                entry(6, 10, 20),
+            ],
+        );
+        assert_eq!(
+            check_partitions(&table),
+            vec![
+                Problem::Backwards { number: 3 },
+                Problem::OutsideUsable {
+                    number: 4,
+                    start: 34,
+                    end: 2014
+                },
+                Problem::OutsideUsable {
+                    number: 5,
+                    start: 34,
+                    end: 2014
+                },
+                Problem::Overlap {
+                    first: 1,
+                    second: 2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mismatch() {
+        let primary = table(Location::Primary, &[entry(2, 34, 1000)]);
+        let mut backup = table(Location::Backup, &[entry(2, 34, 1001)]);
+        backup.header.disk_uuid = uuid(9);
+        backup.header.last_usable = 2000;
+        assert_eq!(
+            check_match(&primary, &backup),
+            vec![
+                Problem::Mismatch {
+                    field: "disk Uuids"
+                },
+                Problem::Mismatch {
+                    field: "last usable LBAs"
+                },
+                Problem::Mismatch {
+                    field: "partition entries"
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn mbr_problems() {
+        let info = info();
+        let check = |mbr: Vec<u8>| check_mbr(&mut Cursor::new(mbr), &info).unwrap();
+        let mut missing = mbr(2047);
+        missing[446 + 4] = 0x83;
+        assert_eq!(check(missing), vec![Problem::MbrNotProtective]);
+        assert_eq!(
+            check(mbr(1000)),
+            vec![Problem::MbrProtectiveRange {
+                start: 1,
+                size: 1000,
+                expected_size: 2047
+            }]
+        );
+        let mut unsigned = mbr(2047);
+        unsigned[510] = 0;
+        assert_eq!(check(unsigned), vec![Problem::MbrSignature]);
+    }
+
+    #[test]
+    fn too_small() {
+        let info = Info {
+            disk_size: Size::from_bytes(100),
+            ..info()
+        };
+        assert_eq!(
+            verify(Cursor::new(vec![0; 100]), &info).unwrap(),
+            vec![Problem::TooSmall { size: 100 }]
+        );
+    }
+}
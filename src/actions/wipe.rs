@@ -0,0 +1,193 @@
+//! Filesystem signature detection and wiping, interface agnostic.
+use anyhow::Result;
+use std::io::{prelude::*, SeekFrom};
+use tracing::{debug, info};
+
+/// Where a signature is, relative to the start of a range.
+#[derive(Debug, Copy, Clone)]
+enum At {
+    /// Fixed offset from the start.
+    Start(u64),
+
+    /// Linux MD RAID 0.90 superblock, 64 KiB aligned, 64 KiB from the end.
+    Md090,
+
+    /// Linux MD RAID 1.0 superblock, 4 KiB aligned, 8 KiB from the end.
+    Md10,
+}
+
+impl At {
+    /// Offset of the signature in a range of `len` bytes.
+    fn offset(self, len: u64) -> Option<u64> {
+        match self {
+            At::Start(off) => Some(off),
+            At::Md090 => (len & !0xFFFF).checked_sub(0x10000),
+            At::Md10 => len.checked_sub(0x2000).map(|off| off & !0xFFF),
+        }
+    }
+}
+
+/// A known on-disk signature.
+struct Signature {
+    name: &'static str,
+    at: At,
+    magic: &'static [u8],
+}
+
+const MD_MAGIC: &[u8] = &[0xFC, 0x4E, 0x2B, 0xA9];
+
+/// Known filesystem, RAID, and encryption signatures.
+const SIGNATURES: &[Signature] = &[
+    Signature {
+        name: "ext4",
+        at: At::Start(0x438),
+        magic: &[0x53, 0xEF],
+    },
+    Signature {
+        name: "xfs",
+        at: At::Start(0),
+        magic: b"XFSB",
+    },
+    Signature {
+        name: "btrfs",
+        at: At::Start(0x10040),
+        magic: b"_BHRfS_M",
+    },
+    Signature {
+        name: "f2fs",
+        at: At::Start(0x400),
+        magic: &[0x10, 0x20, 0xF5, 0xF2],
+    },
+    Signature {
+        name: "vfat",
+        at: At::Start(0x52),
+        magic: b"FAT32   ",
+    },
+    Signature {
+        name: "vfat",
+        at: At::Start(0x36),
+        magic: b"FAT16   ",
+    },
+    Signature {
+        name: "vfat",
+        at: At::Start(0x36),
+        magic: b"FAT12   ",
+    },
+    Signature {
+        name: "exfat",
+        at: At::Start(3),
+        magic: b"EXFAT   ",
+    },
+    Signature {
+        name: "ntfs",
+        at: At::Start(3),
+        magic: b"NTFS    ",
+    },
+    Signature {
+        name: "iso9660",
+        at: At::Start(0x8001),
+        magic: b"CD001",
+    },
+    Signature {
+        name: "squashfs",
+        at: At::Start(0),
+        magic: b"hsqs",
+    },
+    Signature {
+        name: "swap",
+        at: At::Start(0xFF6),
+        magic: b"SWAPSPACE2",
+    },
+    Signature {
+        name: "swap",
+        at: At::Start(0xFF6),
+        magic: b"SWAP-SPACE",
+    },
+    Signature {
+        name: "crypto_LUKS",
+        at: At::Start(0),
+        magic: b"LUKS\xBA\xBE",
+    },
+    Signature {
+        name: "crypto_LUKS",
+        at: At::Start(0x4000),
+        magic: b"SKUL\xBA\xBE",
+    },
+    Signature {
+        name: "LVM2_member",
+        at: At::Start(0x218),
+        magic: b"LVM2 001",
+    },
+    Signature {
+        name: "linux_raid_member",
+        at: At::Start(0),
+        magic: MD_MAGIC,
+    },
+    Signature {
+        name: "linux_raid_member",
+        at: At::Start(0x1000),
+        magic: MD_MAGIC,
+    },
+    Signature {
+        name: "linux_raid_member",
+        at: At::Md090,
+        magic: MD_MAGIC,
+    },
+    Signature {
+        name: "linux_raid_member",
+        at: At::Md10,
+        magic: MD_MAGIC,
+    },
+];
+
+/// A signature found by [`find_signatures`].
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub name: &'static str,
+
+    /// Absolute offset, in bytes.
+    pub offset: u64,
+
+    pub magic: &'static [u8],
+}
+
+/// Find known signatures in the `len` bytes at `offset` in `source`.
+pub fn find_signatures<R: Read + Seek>(
+    source: &mut R,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<Found>> {
+    let mut found = Vec::new();
+    let mut buf = Vec::new();
+    for sig in SIGNATURES {
+        let off = match sig.at.offset(len) {
+            Some(off) if off + sig.magic.len() as u64 <= len => off,
+            _ => continue,
+        };
+        buf.resize(sig.magic.len(), 0);
+        source.seek(SeekFrom::Start(offset + off))?;
+        if let Err(e) = source.read_exact(&mut buf) {
+            debug!(%e, name = sig.name, off, "Couldn't read signature");
+            continue;
+        }
+        if buf == sig.magic {
+            found.push(Found {
+                name: sig.name,
+                offset: offset + off,
+                magic: sig.magic,
+            });
+        }
+    }
+    Ok(found)
+}
+
+/// Zero the signatures in `found`.
+pub fn wipe_signatures<W: Write + Seek>(dest: &mut W, found: &[Found]) -> Result<()> {
+    for sig in found {
+        info!(name = sig.name, offset = sig.offset, "Wiping signature");
+        dest.seek(SeekFrom::Start(sig.offset))?;
+        dest.write_all(&vec![0; sig.magic.len()])?;
+    }
+    dest.flush()?;
+    Ok(())
+}
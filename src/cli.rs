@@ -1,8 +1,8 @@
 //! Code for the CLI Interface
 use crate::{actions::*, Info};
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use parts::types::*;
-use std::{ffi::OsStr, fs};
+use std::{ffi::OsStr, fs, io, path::Path};
 use structopt::StructOpt;
 use tracing::{error, info, metadata::Metadata, Level};
 use tracing_subscriber::{layer, layer::SubscriberExt, FmtSubscriber};
@@ -19,47 +19,474 @@ impl layer::Layer<FmtSubscriber> for VerboseFilter {
     }
 }
 
+/// Open `info.path` for reading, and for writing unless `dry_run`.
+fn open(info: &Info, dry_run: bool) -> Result<fs::File> {
+    let mut f = fs::OpenOptions::new();
+    f.read(true);
+    if !dry_run {
+        f.write(true);
+    }
+    Ok(f.open(&info.path)?)
+}
+
+/// Find known signatures in the `len` bytes at `offset`, and wipe them unless
+/// `dry_run`.
+///
+/// What was found is written to stdout.
+fn wipe_range(f: &mut fs::File, info: &Info, offset: u64, len: u64, dry_run: bool) -> Result<()> {
+    let found = wipe::find_signatures(f, offset, len)?;
+    if !dry_run {
+        wipe::wipe_signatures(f, &found)?;
+    }
+    for sig in found {
+        let magic: Vec<String> = sig.magic.iter().map(|b| format!("{:02x}", b)).collect();
+        println!(
+            "{}: {} bytes {} erased at offset {:#010x} ({}): {}",
+            info.path.display(),
+            sig.magic.len(),
+            if dry_run { "would be" } else { "were" },
+            sig.offset,
+            sig.name,
+            magic.join(" ")
+        );
+    }
+    Ok(())
+}
+
 /// Handle CLI subcommand actions.
 fn handle_cmd(cmd: Commands, info: Info, dry_run: bool) -> Result<()> {
     match cmd {
-        Commands::Create { uuid } => {
+        Commands::Create {
+            uuid,
+            wipe_signatures,
+        } => {
+            if wipe_signatures {
+                let mut f = open(&info, dry_run)?;
+                wipe_range(&mut f, &info, 0, info.disk_size.as_bytes(), dry_run)?;
+            }
             let gpt = new_gpt(uuid, &info);
             if !dry_run {
                 write_gpt_path(&gpt, &info)?;
             }
         }
+        Commands::CreateImage {
+            size: _,
+            uuid,
+            partitions,
+        } => {
+            check_image_size(&info)?;
+            let registry = registry::Registry::load()?;
+            let mut gpt = new_gpt(uuid, &info);
+            let usable = (first_usable(&info), last_usable(&info));
+            for part in partitions {
+                let partition_type = registry.lookup(&part.partition_type)?;
+                let (start, remaining) = next_start(&gpt, &info, usable);
+                let end = match part.size {
+                    Some(size) => End::Rel(Size::from_bytes(size)),
+                    None => End::Rel(remaining),
+                };
+                add_part(
+                    &mut gpt,
+                    &info,
+                    None,
+                    part.name.as_deref(),
+                    partition_type,
+                    start,
+                    end,
+                )?;
+            }
+            if !dry_run {
+                create_image(&info)?;
+                write_gpt_path(&gpt, &info)?;
+            }
+        }
         Commands::AddPartition {
             start,
+            placement,
             end,
             size,
+            name,
             partition_type,
             uuid,
+            number,
+            force,
+            wipe_signatures,
         } => {
-            let mut f = {
-                let mut f = fs::OpenOptions::new();
-                f.read(true);
-                if !dry_run {
-                    f.write(true);
+            let partition_type = registry::Registry::load()?.lookup(&partition_type)?;
+            let mut f = open(&info, dry_run)?;
+            let mut gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let attributes = read_attributes(&mut f, &info)?;
+            let usable = read_usable(&mut f, &info)?;
+            let (start, end) = match placement {
+                Some(placement) => place_in_free(&gpt, &info, usable, placement, size)?,
+                None => {
+                    // CLI provided size, or next aligned.
+                    let (next, remaining) = next_start(&gpt, &info, usable);
+                    let start = start.map(Offset).unwrap_or(next);
+                    // If end, absolute. If size, relative. If neither, remaining size.
+                    let end = match (end, size) {
+                        (Some(end), None) => End::Abs(Offset(end)),
+                        (None, Some(size)) => End::Rel(Size::from_bytes(size)),
+                        (None, None) => End::Rel(remaining),
+                        _ => unreachable!("Clap conflicts prevent this"),
+                    };
+                    (start, end)
                 }
-                f.open(&info.path)?
             };
+            let part = add_part(
+                &mut gpt,
+                &info,
+                uuid,
+                name.as_deref(),
+                partition_type,
+                start,
+                end,
+            )?;
+            match number {
+                // Written directly, so existing entries keep their numbers.
+                Some(number) => {
+                    let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+                    check_primary(&raw, force)?;
+                    place_part(&mut raw, &part, partition_type, number)?;
+                    if !dry_run {
+                        raw.write(&mut f, info.block_size)?;
+                    }
+                }
+                None => {
+                    if !dry_run {
+                        write_gpt_attributes(&gpt, &mut f, &info, &numbers, &attributes)?;
+                    }
+                }
+            }
+            if wipe_signatures {
+                let block_size = info.block_size.get();
+                let offset = part.start().0 * block_size;
+                let len = (part.end().0 - part.start().0 + 1) * block_size;
+                wipe_range(&mut f, &info, offset, len, dry_run)?;
+            }
+        }
+        Commands::DeletePartition { partition, force } => {
+            let mut f = open(&info, dry_run)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            check_primary(&raw, force)?;
+            remove_part(&gpt, &mut raw, &info, &partition)?;
+            if !dry_run {
+                raw.write(&mut f, info.block_size)?;
+            }
+        }
+        Commands::ResizePartition {
+            partition,
+            end,
+            size,
+        } => {
+            let mut f = open(&info, dry_run)?;
             let mut gpt = read_gpt(&mut f, &info)?;
-            // CLI provided size, or next aligned.
-            let start: Offset = start
-                .map(Offset)
-                .unwrap_or_else(|| gpt.next_usable_aligned() * info.block_size);
-            // If end, absolute. If size, relative. If neither, remaining size.
+            let numbers = read_numbers(&mut f, &info)?;
+            let attributes = read_attributes(&mut f, &info)?;
+            let usable = read_usable(&mut f, &info)?;
+            // If end, absolute. If size, relative. If neither, all free space after.
             let end = match (end, size) {
-                (Some(end), None) => End::Abs(Offset(end)),
-                (None, Some(size)) => End::Rel(Size::from_bytes(size)),
-                (None, None) => End::Rel(gpt.remaining()),
+                (Some(end), None) => Some(End::Abs(Offset(end))),
+                (None, Some(size)) => Some(End::Rel(Size::from_bytes(size))),
+                (None, None) => None,
                 _ => unreachable!("Clap conflicts prevent this"),
             };
-            add_part(&mut gpt, &info, uuid, partition_type, start, end)?;
+            resize_part(&mut gpt, &info, usable, &numbers, &partition, end)?;
+            eprintln!("Only the partition entry is changed, partition contents are not touched");
+            if !dry_run {
+                write_gpt_attributes(&gpt, f, &info, &numbers, &attributes)?;
+            }
+        }
+        Commands::MovePartition { partition, start } => {
+            let mut f = open(&info, dry_run)?;
+            let mut gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let attributes = read_attributes(&mut f, &info)?;
+            let usable = read_usable(&mut f, &info)?;
+            let (old, new) =
+                move_part(&mut gpt, &info, usable, &numbers, &partition, Offset(start))?;
+            if !dry_run {
+                let block_size = info.block_size.get();
+                let len = (old.end().0 - old.start().0 + 1) * block_size;
+                data::copy_within(
+                    &mut f,
+                    old.start().0 * block_size,
+                    new.start().0 * block_size,
+                    len,
+                )?;
+                f.sync_all()?;
+                write_gpt_attributes(&gpt, f, &info, &numbers, &attributes)?;
+            }
+        }
+        Commands::EditPartition {
+            partition,
+            name,
+            partition_type,
+            uuid,
+        } => {
+            let partition_type = match partition_type {
+                Some(t) => Some(registry::Registry::load()?.lookup(&t)?),
+                None => None,
+            };
+            let mut f = open(&info, dry_run)?;
+            let mut gpt = read_gpt(&mut f, &info)?;
+            let mut numbers = read_numbers(&mut f, &info)?;
+            let mut attributes = read_attributes(&mut f, &info)?;
+            edit_part(
+                &mut gpt,
+                &info,
+                &mut numbers,
+                &mut attributes,
+                &partition,
+                name.as_deref(),
+                partition_type,
+                uuid,
+            )?;
+            if !dry_run {
+                write_gpt_attributes(&gpt, f, &info, &numbers, &attributes)?;
+            }
+        }
+        Commands::Attributes {
+            partition,
+            set,
+            clear,
+            force,
+        } => {
+            let mut f = open(&info, dry_run)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            let changed = !set.is_empty() || !clear.is_empty();
+            if changed {
+                check_primary(&raw, force)?;
+            }
+            let attributes = update_attributes(&gpt, &mut raw, &partition, &set, &clear)?;
+            if !dry_run && changed {
+                raw.write(&mut f, info.block_size)?;
+            }
+            for attr in Attribute::from_bits(attributes) {
+                println!("{}", attr);
+            }
+        }
+        Commands::SetDiskGuid { uuid, force } => {
+            let mut f = open(&info, dry_run)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            check_primary(&raw, force)?;
+            set_disk_uuid(&mut raw, uuid);
+            if !dry_run {
+                raw.write(&mut f, info.block_size)?;
+            }
+        }
+        Commands::RandomizeGuids {
+            partition,
+            keep_disk,
+            force,
+        } => {
+            let mut f = open(&info, dry_run)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            check_primary(&raw, force)?;
+            let changed = randomize_uuids(&gpt, &mut raw, !keep_disk, &partition)?;
+            if !dry_run {
+                raw.write(&mut f, info.block_size)?;
+            }
+            for (old, new) in changed {
+                println!("{} -> {}", old, new);
+            }
+        }
+        Commands::Print => {
+            let registry = registry::Registry::load()?;
+            let mut f = fs::File::open(&info.path)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let usable = read_usable(&mut f, &info)?;
+            print!("{}", print(&gpt, &info, usable, &numbers, &registry)?);
+        }
+        Commands::Verify => {
+            let problems = verify::verify(fs::File::open(&info.path)?, &info)?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(anyhow!("Found {} problems", problems.len()));
+            }
+            println!("No problems found");
+        }
+        Commands::Repair { from } => {
+            let mut f = open(&info, dry_run)?;
+            for (rebuilt, mut table) in repair(&mut f, &info, from)? {
+                if dry_run {
+                    println!("Would write {} Gpt:", rebuilt);
+                    print!("{}", describe_table(&table)?);
+                } else {
+                    table.write(&mut f, info.block_size)?;
+                }
+            }
+        }
+        Commands::Expand { grow_last, force } => {
+            let mut f = open(&info, dry_run)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            check_primary(&raw, force)?;
+            let old = expand(&mut raw, &info, grow_last)?;
+            if grow_last {
+                eprintln!(
+                    "Only the last partition entry is changed, partition contents are not touched"
+                );
+            }
+            if !dry_run {
+                raw.write(&mut f, info.block_size)?;
+                raw::update_protective_mbr(&mut f, info.block_size, info.disk_size)?;
+                if old != raw.backup.header.this_lba {
+                    let block_size = info.block_size.get();
+                    data::zero(&mut f, old * block_size, block_size)?;
+                }
+            }
+        }
+        Commands::Sort { force } => {
+            let mut f = open(&info, dry_run)?;
+            let mut raw = raw::RawGpt::read(&mut f, info.block_size)?;
+            check_primary(&raw, force)?;
+            let numbers = sort_entries(&mut raw);
+            if !dry_run {
+                raw.write(&mut f, info.block_size)?;
+            }
+            for (old, new) in numbers {
+                println!("{} -> {}", old, new);
+            }
+        }
+        Commands::Replicate {
+            targets,
+            target_block,
+            keep_uuids,
+        } => {
+            let mut f = fs::File::open(&info.path)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let attributes = read_attributes(&mut f, &info)?;
+            // Check every target before writing to any of them.
+            let targets = targets
+                .iter()
+                .map(|target| {
+                    let target = Info::new_path(target, target_block)?;
+                    let (gpt, numbers, attributes) =
+                        replicate(&gpt, &numbers, &attributes, &info, &target, keep_uuids)?;
+                    Ok((target, gpt, numbers, attributes))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            if !dry_run {
+                for (target, gpt, numbers, attributes) in targets {
+                    let path = target.path.display();
+                    info!(%path, %target.block_size, "Writing GPT");
+                    let dest = fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .open(&target.path)
+                        .with_context(|| format!("Couldn't open {}", path))?;
+                    write_gpt_attributes(&gpt, dest, &target, &numbers, &attributes)?;
+                }
+            }
+        }
+        Commands::Zap { keep_mbr, force } => {
+            let mut f = open(&info, dry_run)?;
+            let regions = gpt_regions(&mut f, &info, keep_mbr);
+            if !force && !dry_run {
+                let path = info.path.display();
+                eprint!(
+                    "This will erase the Gpt on {}. Type \"yes\" to continue: ",
+                    path
+                );
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                if answer.trim() != "yes" {
+                    return Err(anyhow!("Aborted"));
+                }
+            }
+            for (name, offset, len) in regions {
+                info!(%name, offset, len, "Erasing");
+                if !dry_run {
+                    data::zero(&mut f, offset, len)?;
+                }
+            }
+        }
+        Commands::ExtractPartition { partition, output } => {
+            let mut f = fs::File::open(&info.path)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let (_, part) = find_part(&gpt, &numbers, &partition)?;
+            let block_size = info.block_size.get();
+            let offset = part.start().0 * block_size;
+            let len = (part.end().0 - part.start().0 + 1) * block_size;
+            let path = output.display();
+            info!(%partition, %path, offset, len, "Extracting partition");
+            if !dry_run {
+                let mut dest = fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&output)
+                    .with_context(|| format!("Couldn't create {}", path))?;
+                data::copy_sparse(&mut f, offset, len, &mut dest)?;
+            }
+        }
+        Commands::WritePartition {
+            partition,
+            input,
+            zero_fill,
+            verify,
+        } => {
+            let mut f = open(&info, dry_run)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let numbers = read_numbers(&mut f, &info)?;
+            let (_, part) = find_part(&gpt, &numbers, &partition)?;
+            let block_size = info.block_size.get();
+            let offset = part.start().0 * block_size;
+            let len = (part.end().0 - part.start().0 + 1) * block_size;
+            let mut source: Box<dyn io::Read> = match &input {
+                Some(input) if input != Path::new("-") => {
+                    let source = fs::File::open(input)
+                        .with_context(|| format!("Couldn't open {}", input.display()))?;
+                    let meta = source.metadata()?;
+                    // Streams are checked while writing.
+                    if meta.is_file() && meta.len() > len {
+                        return Err(anyhow!(
+                            "{} is {} bytes, larger than the {} byte partition",
+                            input.display(),
+                            meta.len(),
+                            len
+                        ));
+                    }
+                    Box::new(source)
+                }
+                _ => Box::new(io::stdin()),
+            };
+            info!(%partition, offset, len, "Writing partition");
             if !dry_run {
-                write_gpt(&gpt, f, &info)?;
+                let (written, crc) = data::write_limited(&mut source, &mut f, offset, len)?;
+                if zero_fill {
+                    data::zero(&mut f, offset + written, len - written)?;
+                }
+                f.sync_all()?;
+                if verify {
+                    data::drop_cache(&f, offset, written)?;
+                    data::verify_crc(&mut f, offset, written, crc)?;
+                }
             }
         }
+        Commands::Header { json } => {
+            let headers = headers(fs::File::open(&info.path)?, &info);
+            print!("{}", print_headers(&headers, json)?);
+        }
+        Commands::Checksum {
+            algorithm,
+            regions,
+            json,
+        } => {
+            let mut f = fs::File::open(&info.path)?;
+            let gpt = read_gpt(&mut f, &info)?;
+            let sums = checksum(&mut f, &gpt, &info, algorithm, regions)?;
+            print!("{}", print_checksums(&sums, json)?);
+        }
         Commands::Dump { format } => {
             let dump = dump(&read_gpt_path(&info)?, format, &info)?;
             if !dry_run {
@@ -71,12 +498,24 @@ fn handle_cmd(cmd: Commands, info: Info, dry_run: bool) -> Result<()> {
             override_block: _,
         } => {
             // TODO: Version cli argument
-            let gpt = restore(format, PartitionInfoVersion::default())?;
+            let (gpt, attributes, block_size) = restore(format, PartitionInfoVersion::default())?;
             // FIXME: impl override_block. Add block_size to Gpt and then use them here.
             if !dry_run {
                 write_gpt_path(&gpt, &info)?;
+                if attributes.values().any(|a| *a != 0) {
+                    // `info` doesn't know the block size unless `block` was given.
+                    let info = if info.block_size.get() == 0 {
+                        Info { block_size, ..info }
+                    } else {
+                        info
+                    };
+                    write_attributes_path(&attributes, &info)?;
+                }
             }
         }
+        Commands::Disks { .. } | Commands::Find { .. } | Commands::Types { .. } => {
+            unreachable!("Handled in handle_args")
+        }
         Commands::Complete { shell } => {
             let mut app = Args::clap();
             let name = app.get_name().to_owned();
@@ -123,7 +562,35 @@ pub fn handle_args() -> Result<CliAction> {
     )?;
     info!(args.verbose, args.dry_run, "Starting");
 
-    if args.cmd.is_some() {
+    if let Some(Commands::Disks { json }) = args.cmd {
+        // Doesn't use `device`
+        print!("{}", print_disks(&disks()?, json)?);
+        Ok(CliAction::Quit)
+    } else if let Some(Commands::Types { json }) = args.cmd {
+        // Doesn't use `device`
+        print!("{}", print_types(&registry::Registry::load()?, json)?);
+        Ok(CliAction::Quit)
+    } else if let Some(Commands::Find {
+        name,
+        uuid,
+        partition_type,
+        json,
+    }) = &args.cmd
+    {
+        // Doesn't use `device`
+        let query = match (name, uuid, partition_type) {
+            (Some(name), ..) => Query::Name(name.clone()),
+            (_, Some(uuid), _) => Query::Uuid(*uuid),
+            (.., Some(t)) => Query::Type(registry::Registry::load()?.lookup(t)?),
+            _ => unreachable!("Required by clap"),
+        };
+        let found = find(&query)?;
+        if found.is_empty() {
+            return Err(anyhow!("Couldn't find {}", query));
+        }
+        print!("{}", print_found(&found, *json)?);
+        Ok(CliAction::Quit)
+    } else if args.cmd.is_some() {
         let info = Info::new_cli(&args)?;
         let cmd = args.cmd.expect("Missing subcommand");
         handle_cmd(cmd, info, args.dry_run)?;
@@ -1,8 +1,8 @@
 //! CLI Argument handling code
-use crate::actions::Format;
+use crate::actions::{data::Algorithm, verify::Location, Attribute, Format, Placement, Selector};
 use anyhow::{anyhow, Result};
 use parts::{types::Size, uuid::Uuid};
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
 use structopt::{
     clap::{AppSettings, Shell},
     StructOpt,
@@ -28,6 +28,64 @@ fn parse_size(arg: &str) -> Result<u64> {
     }
 }
 
+/// Partition alignment, from `--align`.
+#[derive(Clone, Copy, Debug)]
+pub enum Align {
+    /// Detect from the device topology.
+    Auto,
+
+    /// A fixed size, in bytes.
+    Size(u64),
+}
+
+impl FromStr for Align {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Align::Auto),
+            s => Ok(Align::Size(parse_size(s)?)),
+        }
+    }
+}
+
+/// A partition to create with `create-image`.
+///
+/// Parsed from `SIZE[:TYPE[:NAME]]`, where `SIZE` may be empty or `-` to use
+/// the remaining space.
+///
+/// `TYPE` is a partition type Uuid, name, or alias, resolved later by the type
+/// registry.
+#[derive(Clone, Debug)]
+pub struct PartSpec {
+    pub size: Option<u64>,
+    pub partition_type: String,
+    pub name: Option<String>,
+}
+
+impl FromStr for PartSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.splitn(3, ':');
+        let size = match fields.next().map(str::trim) {
+            None | Some("") | Some("-") => None,
+            Some(size) => Some(parse_size(size)?),
+        };
+        let partition_type = match fields.next().map(str::trim) {
+            None | Some("") => "linux",
+            Some(t) => t,
+        }
+        .into();
+        let name = fields.next().map(Into::into);
+        Ok(PartSpec {
+            size,
+            partition_type,
+            name,
+        })
+    }
+}
+
 /// Modern GPT Partition editor
 #[derive(Clone, Debug, StructOpt)]
 #[structopt(global_settings(&[
@@ -49,6 +107,16 @@ pub struct Args {
     #[structopt(short, long, global(true))]
     pub block: Option<u64>,
 
+    /// Alignment of new partitions, in bytes, or `auto`.
+    ///
+    /// You can use the KiB, MiB, GiB, and TiB suffixes here.
+    ///
+    /// `auto` uses 1 MiB, or a multiple of it that suits the minimum and
+    /// optimal IO sizes of `device`, such as a RAID stripe, starting from its
+    /// alignment offset.
+    #[structopt(long, global(true), default_value = "auto")]
+    pub align: Align,
+
     /// Use an interactive TUI interface.
     /// If `device` is not specified, displays a selection.
     #[structopt(short, long, required_unless("subcommand"))]
@@ -86,6 +154,45 @@ pub enum Commands {
         /// Only use this if you know what you're doing.
         #[structopt(long)]
         uuid: Option<Uuid>,
+
+        /// Find and erase known filesystem, RAID, and LUKS signatures at the
+        /// start and end of the device first, like `wipefs`.
+        ///
+        /// What was erased is written to stdout.
+        #[structopt(long)]
+        wipe_signatures: bool,
+    },
+
+    /// Create a new sparse disk image file at `device`, with a new Gpt.
+    ///
+    /// Uses a logical block size of 512 unless `block` is specified.
+    CreateImage {
+        /// Image size, in bytes.
+        ///
+        /// You can use the KiB, MiB, GiB, and TiB suffixes here.
+        /// (The `iB` is optional)
+        #[structopt(long, parse(try_from_str = parse_size))]
+        size: u64,
+
+        /// Use this specific UUID instead of generating a new one.
+        ///
+        /// WARNING: Gpt UUID's must be unique.
+        /// Only use this if you know what you're doing.
+        #[structopt(long)]
+        uuid: Option<Uuid>,
+
+        /// Add a partition, as `SIZE[:TYPE[:NAME]]`.
+        /// Can be passed multiple times.
+        ///
+        /// Partitions are added in order, each starting at the next aligned
+        /// boundary, see `--align`. `SIZE` uses the same suffixes as `size`,
+        /// and may be empty or `-` to use the remaining space. `TYPE` is a
+        /// partition type Uuid, name, or alias, and defaults to Linux
+        /// Filesystem Data. See `types`.
+        ///
+        /// Example: `--part 512M:esp:EFI --part -`
+        #[structopt(long = "part", number_of_values(1))]
+        partitions: Vec<PartSpec>,
     },
 
     /// Add a partition to the Gpt.
@@ -93,10 +200,21 @@ pub enum Commands {
     AddPartition {
         /// Partition start, in bytes.
         ///
-        /// If not specified, the partition starts at the next 1 MiB boundary.
+        /// If not specified, the partition starts at the next aligned boundary,
+        /// see `--align`, or as chosen by `--placement`.
         #[structopt(long)]
         start: Option<u64>,
 
+        /// Where to put the partition in free space, instead of `--start`.
+        ///
+        /// One of `first-fit`, `best-fit` for the smallest free space it fits
+        /// in, `last` for the end of the disk, or the number of a free space,
+        /// as shown by `print`.
+        ///
+        /// Without `--size`, the partition uses all of the chosen free space.
+        #[structopt(long, conflicts_with_all(&["start", "end"]))]
+        placement: Option<Placement>,
+
         /// Partition end, in bytes. Inclusive.
         /// Rounds up to nearest block_size.
         ///
@@ -116,9 +234,15 @@ pub enum Commands {
         #[structopt(long, conflicts_with("end"), parse(try_from_str = parse_size))]
         size: Option<u64>,
 
-        /// Partition type Uuid. Defaults to Linux Filesystem Data
-        #[structopt(short, long, default_value = "0FC63DAF-8483-4772-8E79-3D69D8477DE4")]
-        partition_type: Uuid,
+        /// Partition name.
+        #[structopt(short, long)]
+        name: Option<String>,
+
+        /// Partition type Uuid, name, or alias. See `types`.
+        ///
+        /// Defaults to Linux Filesystem Data.
+        #[structopt(short, long, default_value = "linux")]
+        partition_type: String,
 
         /// Use this specific UUID instead of generating a new one.
         ///
@@ -126,6 +250,374 @@ pub enum Commands {
         /// Only use this if you know what you're doing.
         #[structopt(long)]
         uuid: Option<Uuid>,
+
+        /// Partition number, starting at 1. Must not already be in use.
+        ///
+        /// If not specified, uses the lowest unused number.
+        #[structopt(long)]
+        number: Option<usize>,
+
+        /// With `--number`, write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long, requires("number"))]
+        force: bool,
+
+        /// Find and erase known filesystem, RAID, and LUKS signatures at the
+        /// start and end of the new partition, like `wipefs`.
+        ///
+        /// What was erased is written to stdout.
+        #[structopt(long)]
+        wipe_signatures: bool,
+    },
+
+    /// Delete a partition from the Gpt.
+    ///
+    /// Other partitions keep their numbers.
+    #[structopt(alias("del"))]
+    DeletePartition {
+        /// Partition to delete.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Resize a partition by changing its end.
+    ///
+    /// WARNING: This ONLY changes the partition table entry.
+    /// It does NOT touch the partition contents, any filesystem inside must be
+    /// grown or shrunk separately. Shrink the filesystem BEFORE shrinking the
+    /// partition.
+    #[structopt(alias("resize"))]
+    ResizePartition {
+        /// Partition to resize.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// New partition end, in bytes. Inclusive.
+        ///
+        /// If not specified, uses all free space directly after the partition.
+        #[structopt(long)]
+        end: Option<u64>,
+
+        /// New partition size, in bytes.
+        ///
+        /// You can use the KiB, MiB, GiB, and TiB suffixes here.
+        /// (The `iB` is optional)
+        ///
+        /// Note that partitions can only be specified in terms of the
+        /// logical block size, so this value may be rounded up.
+        ///
+        /// If not specified, uses all free space directly after the partition.
+        #[structopt(long, conflicts_with("end"), parse(try_from_str = parse_size))]
+        size: Option<u64>,
+    },
+
+    /// Move a partition, and its contents, to a new start.
+    ///
+    /// The partition keeps its size. The old and new locations may overlap.
+    ///
+    /// WARNING: If interrupted, the partition contents WILL be left
+    /// half copied.
+    #[structopt(alias("move"))]
+    MovePartition {
+        /// Partition to move.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// New partition start, in bytes.
+        #[structopt(long)]
+        start: u64,
+    },
+
+    /// Edit a partitions name, type, or Uuid.
+    ///
+    /// Only the specified fields are changed.
+    #[structopt(alias("edit"))]
+    EditPartition {
+        /// Partition to edit.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// New partition name.
+        #[structopt(short, long)]
+        name: Option<String>,
+
+        /// New partition type Uuid, name, or alias. See `types`.
+        #[structopt(short = "t", long = "type")]
+        partition_type: Option<String>,
+
+        /// New partition Uuid.
+        ///
+        /// WARNING: Partition UUID's must be unique.
+        /// Only use this if you know what you're doing.
+        #[structopt(long)]
+        uuid: Option<Uuid>,
+    },
+
+    /// List, set, or clear partition attribute bits.
+    ///
+    /// Attributes are given either by bit number, 0 to 63, or by name.
+    /// Known names are `required`, `no-block-io`, `legacy-bios-bootable`,
+    /// and the type specific `read-only`, `shadow-copy`, `hidden`, and
+    /// `no-automount`.
+    ///
+    /// The resulting attributes are written to stdout.
+    #[structopt(alias("attrs"))]
+    Attributes {
+        /// Partition to use.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// Attributes to set.
+        #[structopt(long, number_of_values(1))]
+        set: Vec<Attribute>,
+
+        /// Attributes to clear.
+        #[structopt(long, number_of_values(1))]
+        clear: Vec<Attribute>,
+
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Change the disk Uuid of an existing Gpt.
+    SetDiskGuid {
+        /// New disk Uuid.
+        ///
+        /// WARNING: Gpt UUID's must be unique.
+        /// Only use this if you know what you're doing.
+        #[structopt(long)]
+        uuid: Uuid,
+
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Generate new random disk and partition Uuids.
+    ///
+    /// Useful after cloning a disk, which duplicates them.
+    ///
+    /// The old and new partition Uuids are written to stdout.
+    RandomizeGuids {
+        /// Only change these partitions. Can be passed multiple times.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        ///
+        /// If not specified, every partition is changed.
+        #[structopt(long, number_of_values(1))]
+        partition: Vec<Selector>,
+
+        /// Keep the existing disk Uuid.
+        #[structopt(long)]
+        keep_disk: bool,
+
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Print a human readable table of the partitions and free space.
+    ///
+    /// Start and end are shown both in blocks and bytes.
+    /// Ends are inclusive in blocks, and exclusive in bytes.
+    #[structopt(alias("list"))]
+    Print,
+
+    /// Check the Gpt for problems.
+    ///
+    /// Checks the protective MBR, both headers, their layout, and their CRCs,
+    /// that the backup is at the end of the device and matches the primary,
+    /// and that partitions are in the usable range and don't overlap.
+    ///
+    /// Each problem is written to stdout.
+    /// Exits with an error if any were found.
+    Verify,
+
+    /// Rebuild a damaged copy of the Gpt from the other copy.
+    ///
+    /// With `dry-run`, what would be written is shown on stdout.
+    Repair {
+        /// Copy of the Gpt to rebuild from.
+        ///
+        /// If not specified, uses the only valid copy.
+        #[structopt(long, case_insensitive(true), possible_values(&Location::variants()))]
+        from: Option<Location>,
+    },
+
+    /// Move the backup Gpt to the end of a grown device.
+    ///
+    /// Use this after enlarging a disk image or virtual disk, to make the new
+    /// space usable.
+    Expand {
+        /// Also grow the last partition into the new space.
+        ///
+        /// WARNING: This does NOT touch the partition contents, any filesystem
+        /// inside must be grown separately.
+        #[structopt(long)]
+        grow_last: bool,
+
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Sort partition entries by start, and renumber them without gaps.
+    ///
+    /// The old and new partition numbers are written to stdout.
+    ///
+    /// WARNING: Anything referring to partitions by number, such as
+    /// `/dev/sda2` in fstab, must be updated.
+    Sort {
+        /// Write even if the primary Gpt CRCs are wrong.
+        ///
+        /// WARNING: The backup Gpt is replaced by the damaged primary.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Copy the partition layout of `device` to other devices.
+    ///
+    /// The copies get new disk and partition Uuids.
+    ///
+    /// WARNING: This WILL IMMEDIATELY overwrite ANY existing Gpt on the
+    /// targets
+    Replicate {
+        /// Device or file to copy the partition layout to.
+        /// Can be passed multiple times.
+        #[structopt(long = "target", required(true), number_of_values(1))]
+        targets: Vec<PathBuf>,
+
+        /// Logical Block Size of the targets. Overrides autodetection.
+        #[structopt(long)]
+        target_block: Option<u64>,
+
+        /// Keep the same disk and partition Uuids.
+        ///
+        /// WARNING: Gpt and Partition UUID's must be unique.
+        /// Only use this if you know what you're doing.
+        #[structopt(long)]
+        keep_uuids: bool,
+    },
+
+    /// Erase the Gpt, both headers and partition entry arrays, and the
+    /// protective MBR.
+    ///
+    /// Asks for confirmation unless `force` is passed.
+    Zap {
+        /// Don't erase the protective MBR.
+        #[structopt(long)]
+        keep_mbr: bool,
+
+        /// Don't ask for confirmation.
+        #[structopt(short, long)]
+        force: bool,
+    },
+
+    /// Copy a partitions contents to a file.
+    ///
+    /// Blocks of zeros are left as holes, so the file is sparse.
+    #[structopt(alias("extract"))]
+    ExtractPartition {
+        /// Partition to extract.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// File to write to. Overwritten if it exists.
+        #[structopt(short, long)]
+        output: PathBuf,
+    },
+
+    /// Write a file, or stdin, into a partition.
+    ///
+    /// Fails if the input is larger than the partition.
+    /// When reading stdin this can only be detected while writing, after the
+    /// partition has been filled.
+    WritePartition {
+        /// Partition to write to.
+        ///
+        /// Either the partition number, starting at 1, partition Uuid, or
+        /// partition name.
+        #[structopt(long)]
+        partition: Selector,
+
+        /// File to read from. Reads stdin if not specified, or `-`.
+        #[structopt(short, long)]
+        input: Option<PathBuf>,
+
+        /// Fill the rest of the partition with zeros.
+        #[structopt(long)]
+        zero_fill: bool,
+
+        /// Read the data back from the device afterwards, bypassing the cache,
+        /// and check it matches.
+        #[structopt(long)]
+        verify: bool,
+    },
+
+    /// Show the raw primary and backup Gpt header fields, side by side.
+    ///
+    /// Works even if the headers are damaged.
+    Header {
+        /// Output JSON instead of text.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Hash the contents of each partition.
+    ///
+    /// Partitions are identified by their Uuid.
+    Checksum {
+        /// Hash algorithm to use.
+        #[structopt(
+            long,
+            default_value = "sha256",
+            case_insensitive(true),
+            possible_values(&Algorithm::variants())
+        )]
+        algorithm: Algorithm,
+
+        /// Also hash the protective MBR, both Gpt copies, and free space.
+        #[structopt(long)]
+        regions: bool,
+
+        /// Output JSON instead of text.
+        #[structopt(long)]
+        json: bool,
     },
 
     /// Dump the GPT Label to disk. Writes to stdout.
@@ -153,6 +645,57 @@ pub enum Commands {
         override_block: bool,
     },
 
+    /// List connected block devices.
+    ///
+    /// `device` is ignored.
+    Disks {
+        /// Output JSON instead of a table.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// Search connected disks for a partition, and print where it is.
+    ///
+    /// Prints the disk, kernel partition device, partition number, Uuid, and
+    /// name of each match.
+    ///
+    /// `device` is ignored.
+    Find {
+        /// Partition name to search for.
+        #[structopt(
+            short,
+            long,
+            required_unless_one(&["uuid", "partition-type"]),
+            conflicts_with_all(&["uuid", "partition-type"])
+        )]
+        name: Option<String>,
+
+        /// Partition Uuid to search for.
+        #[structopt(long, conflicts_with("partition-type"))]
+        uuid: Option<Uuid>,
+
+        /// Partition type Uuid, name, or alias to search for. See `types`.
+        #[structopt(short = "t", long = "type")]
+        partition_type: Option<String>,
+
+        /// Output JSON instead of text.
+        #[structopt(long)]
+        json: bool,
+    },
+
+    /// List known partition types, and their aliases.
+    ///
+    /// Extra types can be added in `$XDG_CONFIG_HOME/parts/types.json`, or
+    /// `~/.config/parts/types.json`, as a JSON array of objects with `uuid`,
+    /// `name`, and optional `aliases`.
+    ///
+    /// `device` is ignored.
+    Types {
+        /// Output JSON instead of a table.
+        #[structopt(long)]
+        json: bool,
+    },
+
     /// Generate completions to stdout.
     Complete {
         /// Shell
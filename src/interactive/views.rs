@@ -1,7 +1,7 @@
 //! TUI interface
 use super::components::*;
 use crate::{
-    actions::{dump, new_gpt, read_gpt_path, Format},
+    actions::{dump, new_gpt, read_gpt_path, registry::Registry, Format},
     Info,
 };
 use anyhow::{Context, Result};
@@ -170,6 +170,8 @@ fn parts_impl(gpt: Gpt, info: &Info) -> impl View {
     let part_size = TextContent::new("");
     let part_uuid = TextContent::new("");
     let part_type = TextContent::new("");
+    // Unknown types are still shown by Uuid.
+    let registry = Registry::load().unwrap_or_else(|_| Registry::builtin());
     let info = vec![
         TextView::new_with_content(part_name.clone()),
         TextView::new_with_content(part_start.clone()),
@@ -196,7 +198,10 @@ fn parts_impl(gpt: Gpt, info: &Info) -> impl View {
                 .get_appropriate_unit(true)
         ));
         part_uuid.set_content(format!("UUID: {}", part.uuid()));
-        part_type.set_content(format!("Type: {}", part.partition_type()));
+        part_type.set_content(format!(
+            "Type: {}",
+            registry.describe(part.partition_type().to_uuid())
+        ));
         //
         let _ = Uuid::nil();
         type _A = PartitionBuilder;
@@ -2,12 +2,96 @@
 use anyhow::{anyhow, Result};
 use linapi::system::devices::block::{Block, Error};
 use parts::types::*;
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
 
 mod actions;
 mod cli;
 mod interactive;
 
+/// Name of the device file at `path`
+fn file_name(path: &Path) -> Result<String> {
+    Ok(path
+        .file_stem()
+        .ok_or_else(|| anyhow!("Invalid device file"))?
+        .to_str()
+        .ok_or_else(|| anyhow!("Invalid UTF-8 in device file name"))?
+        .to_owned())
+}
+
+/// Default partition alignment, in bytes.
+pub const DEFAULT_ALIGNMENT: u64 = 1024 * 1024;
+
+/// Greatest common divisor of `a` and `b`.
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Least common multiple of `a` and `b`, ignoring zero.
+fn lcm(a: u64, b: u64) -> u64 {
+    match (a, b) {
+        (0, x) | (x, 0) => x,
+        (a, b) => a / gcd(a, b) * b,
+    }
+}
+
+/// Read a number from the sysfs attribute `attr` of the block device `name`.
+///
+/// Missing or invalid attributes are treated as 0.
+fn sysfs_u64(name: &str, attr: &str) -> u64 {
+    let path = Path::new("/sys/class/block").join(name).join(attr);
+    fs::read_to_string(&path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Largest partition alignment used, in bytes.
+///
+/// Some devices report nonsense optimal IO sizes, such as `33553920`, whose
+/// alignment would be absurd.
+const MAX_ALIGNMENT: u64 = 16 * 1024 * 1024;
+
+/// Partition alignment for a device with these sizes, in bytes.
+///
+/// Alignment is a multiple of [`DEFAULT_ALIGNMENT`], the logical and physical
+/// block sizes, and the minimum IO size.
+///
+/// Like util-linux, the optimal IO size is only used if it's a multiple of the
+/// minimum IO size and physical block size, and the alignment stays within
+/// [`MAX_ALIGNMENT`].
+///
+/// Sizes of 0 are unknown, and ignored.
+fn alignment(block_size: u64, physical: u64, min: u64, opt: u64) -> u64 {
+    let align = [block_size, physical, min]
+        .iter()
+        .fold(DEFAULT_ALIGNMENT, |acc, x| lcm(acc, *x));
+    let fits = |size: u64| size == 0 || opt.is_multiple_of(size);
+    if opt == 0 || !fits(min) || !fits(physical) || lcm(align, opt) > MAX_ALIGNMENT {
+        return align;
+    }
+    lcm(align, opt)
+}
+
+/// Partition alignment and alignment offset for `block`, in bytes.
+///
+/// See [`alignment`], using the device's topology in sysfs.
+fn topology(block: &Block, block_size: u64) -> (u64, u64) {
+    let name = block.name();
+    let physical = sysfs_u64(name, "queue/physical_block_size");
+    let min = sysfs_u64(name, "queue/minimum_io_size");
+    let opt = sysfs_u64(name, "queue/optimal_io_size");
+    let offset = sysfs_u64(name, "alignment_offset");
+    let align = alignment(block_size, physical, min, opt);
+    (align, offset % align)
+}
+
 /// General information on the device
 #[derive(Debug, Clone)]
 pub struct Info {
@@ -16,69 +100,111 @@ pub struct Info {
     pub disk_size: Size,
     pub model: String,
     pub name: String,
+
+    /// Partition alignment, in bytes.
+    pub alignment: u64,
+
+    /// Offset of the first aligned byte on the device, in bytes.
+    pub alignment_offset: u64,
 }
 
 impl Info {
     /// Get information on a device from CLI args
     pub fn new_cli(args: &cli::args::Args) -> Result<Info> {
-        let block = match Block::from_dev(&args.device) {
+        // Needed because `block_size` can be None for Restore,
+        // and clap will ensure that it's provided if `override_block`
+        // is passed.
+        //
+        // Example cmd: `cargo run -- /tmp/disk2.img restore < /tmp/test`
+        // Which MUST work correctly.
+        //
+        // For other commands we want the default auto behavior.
+        let block = match (args.block, &args.cmd) {
+            (None, Some(cli::args::Commands::Restore { .. })) => Some(0),
+            (block, _) => block,
+        };
+        // The image doesn't exist yet, so there's nothing to detect.
+        let mut info = if let Some(cli::args::Commands::CreateImage { size, .. }) = args.cmd {
+            let block_size = block.unwrap_or(512);
+            Info {
+                path: args.device.clone(),
+                block_size: BlockSize::new(block_size),
+                disk_size: Size::from_bytes(size),
+                model: String::new(),
+                name: file_name(&args.device)?,
+                alignment: lcm(DEFAULT_ALIGNMENT, block_size),
+                alignment_offset: 0,
+            }
+        } else {
+            Info::new_path(&args.device, block)?
+        };
+        if let cli::args::Align::Size(align) = args.align {
+            let block_size = info.block_size.get();
+            // Restore detects the block size later, so can't be checked here.
+            if align == 0 || (block_size != 0 && !align.is_multiple_of(block_size)) {
+                return Err(anyhow!(
+                    "Alignment must be a multiple of the {} byte block size",
+                    block_size
+                ));
+            }
+            info.alignment = align;
+            info.alignment_offset = 0;
+        }
+        Ok(info)
+    }
+
+    /// Get information on the device or file at `path`.
+    ///
+    /// If `block` is `None`, the logical block size is detected from the
+    /// device.
+    pub fn new_path(path: &Path, block: Option<u64>) -> Result<Info> {
+        let block_dev = match Block::from_dev(path) {
             Ok(block) => Some(block),
             Err(Error::InvalidArg(_)) => None,
             Err(e) => return Err(e.into()),
         };
+        let block_size = match block {
+            Some(s) => s,
+            None => block_dev
+                .as_ref() //
+                .ok_or_else(|| anyhow!("Couldn't automatically determine logical block size"))?
+                .logical_block_size()?,
+        };
+        let (alignment, alignment_offset) = match block_dev.as_ref() {
+            Some(block) => topology(block, block_size),
+            None => (lcm(DEFAULT_ALIGNMENT, block_size), 0),
+        };
         Ok(Info {
-            path: args.device.clone(),
-            block_size: BlockSize::new(match args.block {
-                Some(s) => s,
-                None => {
-                    // Needed because `block_size` can be None for Restore,
-                    // and clap will ensure that it's provided if `override_block`
-                    // is passed.
-                    //
-                    // Example cmd: `cargo run -- /tmp/disk2.img restore < /tmp/test`
-                    // Which MUST work correctly.
-                    //
-                    // For other commands we want the default auto behavior.
-                    if let Some(cli::args::Commands::Restore { .. }) = args.cmd {
-                        0
-                    } else {
-                        block
-                            .as_ref() //
-                            .ok_or_else(|| {
-                                anyhow!("Couldn't automatically determine logical block size")
-                            })?
-                            .logical_block_size()?
-                    }
-                }
-            }),
-            disk_size: Size::from_bytes(match block.as_ref() {
+            path: path.to_path_buf(),
+            block_size: BlockSize::new(block_size),
+            disk_size: Size::from_bytes(match block_dev.as_ref() {
                 Some(block) => block.size()?,
-                None => fs::metadata(&args.device)?.len(),
+                None => fs::metadata(path)?.len(),
             }),
-            model: match block.as_ref() {
+            model: match block_dev.as_ref() {
                 Some(block) => block.model()?.unwrap_or_default(),
                 None => String::new(),
             },
-            name: args
-                .device
-                .file_stem()
-                .ok_or_else(|| anyhow!("Invalid device file"))?
-                .to_str()
-                .ok_or_else(|| anyhow!("Invalid UTF-8 in device file name"))?
-                .to_owned(),
+            name: file_name(path)?,
+            alignment,
+            alignment_offset,
         })
     }
 
     /// Get information on a device from a [`Block`]
     pub fn new_block(block: &Block) -> Result<Info> {
+        let block_size = block.logical_block_size()?;
+        let (alignment, alignment_offset) = topology(block, block_size);
         Ok(Info {
             path: block
                 .dev_path()?
                 .ok_or_else(|| anyhow!("Couldn't get device file"))?,
-            block_size: BlockSize::new(block.logical_block_size()?),
+            block_size: BlockSize::new(block_size),
             disk_size: Size::from_bytes(block.size()?),
             model: block.model()?.unwrap_or_default(),
             name: block.name().to_owned(),
+            alignment,
+            alignment_offset,
         })
     }
 }
@@ -90,3 +216,35 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn alignment_default() {
+        assert_eq!(alignment(512, 512, 512, 0), MIB);
+        assert_eq!(alignment(4096, 4096, 4096, 0), MIB);
+        assert_eq!(alignment(512, 0, 0, 0), MIB);
+    }
+
+    #[test]
+    fn alignment_io_sizes() {
+        assert_eq!(alignment(512, 4096, 4 * MIB, 0), 4 * MIB);
+        // RAID with a 512 KiB chunk and 3 data disks.
+        assert_eq!(alignment(512, 512, 512 * 1024, 3 * 512 * 1024), 3 * MIB);
+    }
+
+    #[test]
+    fn alignment_bogus_optimal() {
+        // Reported by some USB bridges.
+        assert_eq!(alignment(512, 512, 512, 33553920), MIB);
+        assert_eq!(alignment(512, 4096, 4096, 33553920), MIB);
+        // Not a multiple of the minimum IO size.
+        assert_eq!(alignment(512, 512, 4096, 3 * 512), MIB);
+        // Valid, but the alignment would be 32 GiB.
+        assert_eq!(alignment(512, 512, 512, 512 * 32767), MIB);
+    }
+}